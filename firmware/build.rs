@@ -7,11 +7,19 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also captures the identity of the build (version, git commit, profile,
+//! timestamp and board) into `build_info.rs`, which is included by the
+//! `build_info` module of the firmware, and the end of the firmware in flash
+//! into `memory_layout.rs`, which lets the config store check that its
+//! region matches what `memory.x` reserves.
 
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -33,4 +41,146 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+
+    write_build_info(out);
+    write_memory_layout(out);
+}
+
+fn write_memory_layout(out: &Path) {
+    let memory = include_str!("memory.x");
+    let region = |name: &str| {
+        memory
+            .lines()
+            .map(str::trim)
+            .find(|line| line.split_whitespace().next() == Some(name))
+            .unwrap_or_else(|| panic!("memory.x has no {name} region"))
+    };
+    let attribute = |line: &str, name: &str| {
+        let start = line.find(name).unwrap() + name.len();
+        let value = line[start..].trim_start().strip_prefix('=').unwrap();
+        eval(value.split(',').next().unwrap())
+    };
+
+    let boot2 = region("BOOT2");
+    let flash = region("FLASH");
+    let flash_start = attribute(boot2, "ORIGIN");
+    let firmware_end = attribute(flash, "ORIGIN") + attribute(flash, "LENGTH");
+
+    let mut file = File::create(out.join("memory_layout.rs")).unwrap();
+    writeln!(
+        file,
+        "pub const FIRMWARE_END: u32 = {:#x};",
+        firmware_end - flash_start
+    )
+    .unwrap();
+}
+
+/// Evaluates the sums and differences of sizes `memory.x` uses, like `2048K - 0x100`
+fn eval(expression: &str) -> u32 {
+    let mut total = 0;
+    let mut sign = 1;
+
+    for token in expression.split_whitespace() {
+        match token {
+            "+" => sign = 1,
+            "-" => sign = -1,
+            _ => {
+                let (digits, scale) = match token.as_bytes().last() {
+                    Some(b'K') => (&token[..token.len() - 1], 1024),
+                    Some(b'M') => (&token[..token.len() - 1], 1024 * 1024),
+                    _ => (token, 1),
+                };
+                let value = match digits.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => digits.parse(),
+                }
+                .unwrap_or_else(|_| panic!("can not parse {token:?} in memory.x"));
+
+                total += sign * value * scale;
+            }
+        }
+    }
+
+    total.try_into().unwrap()
+}
+
+fn write_build_info(out: &Path) {
+    let git_hash = git(&["rev-parse", "HEAD"])
+        .and_then(|hash| parse_git_hash(&hash))
+        .unwrap_or([0; 20]);
+    let git_dirty = git(&["status", "--porcelain"]).is_some_and(|status| !status.is_empty());
+
+    // Honor SOURCE_DATE_EPOCH so reproducible builds stay reproducible
+    let build_timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        });
+
+    let build_profile = match env::var("PROFILE").as_deref() {
+        Ok("debug") => "Debug",
+        Ok("release") => "Release",
+        _ => "Unknown",
+    };
+    let board = env::var("DC_MOTOR_BOARD").unwrap_or_else(|_| "default".to_owned());
+
+    let version = |name| env::var(name).unwrap().parse::<u16>().unwrap();
+
+    let mut file = File::create(out.join("build_info.rs")).unwrap();
+    writeln!(
+        file,
+        "pub const VERSION: SemanticVersion = SemanticVersion {{ major: {}, minor: {}, patch: {} }};",
+        version("CARGO_PKG_VERSION_MAJOR"),
+        version("CARGO_PKG_VERSION_MINOR"),
+        version("CARGO_PKG_VERSION_PATCH"),
+    )
+    .unwrap();
+    writeln!(file, "pub const GIT_HASH: [u8; 20] = {git_hash:?};").unwrap();
+    writeln!(file, "pub const GIT_DIRTY: bool = {git_dirty};").unwrap();
+    writeln!(
+        file,
+        "pub const BUILD_PROFILE: BuildProfile = BuildProfile::{build_profile};"
+    )
+    .unwrap();
+    writeln!(file, "pub const BUILD_TIMESTAMP: u64 = {build_timestamp};").unwrap();
+    writeln!(file, "pub const BOARD: &str = {board:?};").unwrap();
+
+    // Capture the new identity whenever the sources or the checked out commit change
+    println!("cargo:rerun-if-changed=src");
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/index");
+
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={git_dir}/{head_ref}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=DC_MOTOR_BOARD");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+fn parse_git_hash(hash: &str) -> Option<[u8; 20]> {
+    let mut bytes = [0; 20];
+    if hash.len() != bytes.len() * 2 {
+        return None;
+    }
+
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hash.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(bytes)
 }
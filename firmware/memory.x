@@ -1,5 +1,6 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 16384K - 0x100
+    /* The last 16K hold the device config, see src/config.rs */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 16384K - 0x100 - 16K
     RAM   : ORIGIN = 0x20000000, LENGTH = 264K
 }
@@ -0,0 +1,19 @@
+//! Identity of this firmware build, captured by `build.rs`
+
+use interface::{
+    FixedString, SemanticVersion,
+    c2h::{BuildProfile, SoftwareDataResponse},
+};
+
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+pub fn software_data() -> SoftwareDataResponse {
+    SoftwareDataResponse {
+        version: VERSION,
+        git_hash: GIT_HASH,
+        git_dirty: GIT_DIRTY,
+        build_profile: BUILD_PROFILE,
+        build_timestamp: BUILD_TIMESTAMP,
+        board: FixedString::new(BOARD),
+    }
+}
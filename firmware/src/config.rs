@@ -0,0 +1,291 @@
+//! Device configuration persisted in the last sectors of flash
+//!
+//! Every commit writes a new block to the slot after the newest one, a sector is only erased once
+//! the writes wrap around to it so the wear is spread over the whole region. At boot the valid block
+//! with the highest sequence number is loaded
+
+use core::{cell::RefCell, convert::Infallible};
+
+use crc::{CRC_32_ISCSI, Crc};
+use defmt::{info, unwrap, warn};
+use embassy_rp::{
+    flash::{self, Blocking, ERASE_SIZE, Flash},
+    peripherals::FLASH,
+};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use interface::{DeviceConfig, c2h};
+use postcard::experimental::max_size::MaxSize;
+
+use crate::{
+    control_loop, current, hardware_watchdog,
+    motor_controller::{MOTOR_CONTROLLERS, MotorController},
+    motor_map, safety_watchdog, temperature,
+};
+
+mod memory_layout {
+    include!(concat!(env!("OUT_DIR"), "/memory_layout.rs"));
+}
+
+/// Size of the flash chip, `memory.x` must end the firmware [`REGION_SECTORS`] sectors before it
+const FLASH_SIZE: usize = 16 * 1024 * 1024;
+const SECTOR_SIZE: u32 = ERASE_SIZE as u32;
+/// Sectors at the end of flash holding the config, `memory.x` keeps the firmware out of them
+const REGION_SECTORS: u32 = 4;
+const REGION_START: u32 = FLASH_SIZE as u32 - REGION_SECTORS * SECTOR_SIZE;
+
+// The config region must be exactly the end of flash `memory.x` reserves, checked against the
+// firmware end the build script reads from it
+const _: () = assert!(REGION_START == memory_layout::FIRMWARE_END);
+const _: () = assert!(REGION_START % SECTOR_SIZE == 0);
+
+const SLOT_SIZE: usize = 256;
+const SLOTS_PER_SECTOR: u32 = SECTOR_SIZE / SLOT_SIZE as u32;
+const SLOTS: u32 = REGION_SECTORS * SLOTS_PER_SECTOR;
+
+const MAGIC: [u8; 4] = *b"DCFG";
+/// Bumped whenever the layout of [`DeviceConfig`] changes, blocks of other versions are ignored
+const LAYOUT_VERSION: u16 = 11;
+
+/// Magic, layout version, payload length and sequence number
+const HEADER_SIZE: usize = 12;
+const CRC_SIZE: usize = 4;
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+const _: () = assert!(HEADER_SIZE + DeviceConfig::POSTCARD_MAX_SIZE + CRC_SIZE <= SLOT_SIZE);
+
+struct Store {
+    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+    /// Slot and sequence number of the newest block
+    newest: Option<(u32, u32)>,
+}
+
+static STORE: Mutex<CriticalSectionRawMutex, Option<Store>> = Mutex::new(None);
+
+/// Configuration in use, only persisted by [`commit`]
+static ACTIVE: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<DeviceConfig>> =
+    blocking_mutex::Mutex::new(RefCell::new(DeviceConfig::DEFAULT));
+
+/// Loads the newest valid config from flash, falling back to the defaults
+pub async fn init(flash: FLASH) -> DeviceConfig {
+    let mut store = Store {
+        flash: Flash::new_blocking(flash),
+        newest: None,
+    };
+
+    let mut config = None;
+    for slot in 0..SLOTS {
+        let mut block = [0; SLOT_SIZE];
+        if let Err(err) = store.flash.read(slot_offset(slot), &mut block) {
+            warn!("Failed to read config slot {}: {}", slot, err);
+            continue;
+        }
+
+        let Some((sequence, block_config)) = parse_block(&block) else {
+            continue;
+        };
+
+        if store.newest.is_none_or(|(_, newest)| sequence > newest) {
+            store.newest = Some((slot, sequence));
+            config = block_config;
+        }
+    }
+
+    let config = match config {
+        Some(config) => {
+            info!("Loaded config from flash");
+            config
+        }
+        None => {
+            info!("No valid config in flash, using defaults");
+            DeviceConfig::DEFAULT
+        }
+    };
+
+    ACTIVE.lock(|active| *active.borrow_mut() = config.clone());
+    *STORE.lock().await = Some(store);
+
+    config
+}
+
+pub fn get() -> DeviceConfig {
+    ACTIVE.lock(|active| active.borrow().clone())
+}
+
+/// Modifies the config in use, the caller is responsible for applying the change
+pub fn update<R>(f: impl FnOnce(&mut DeviceConfig) -> R) -> R {
+    ACTIVE.lock(|active| f(&mut active.borrow_mut()))
+}
+
+/// Replaces the config in use and applies it to the motors
+///
+/// The I2C address and UART baud rate take effect on the next boot
+pub async fn apply(config: DeviceConfig) {
+    let Ok(()) = modify::<Infallible>(|active| {
+        *active = config;
+        Ok(())
+    })
+    .await;
+}
+
+/// Modifies the config in use and applies the result to the motors, nothing changes when `f` fails
+pub async fn modify<E>(f: impl FnOnce(&mut DeviceConfig) -> Result<(), E>) -> Result<(), E> {
+    let mut motor_controllers = MOTOR_CONTROLLERS.lock().await;
+
+    let mut config = get();
+    f(&mut config)?;
+
+    if motor_map::get() != config.motor_map {
+        // Motors must not keep running on their new outputs
+        safety_watchdog::disarm(&mut motor_controllers);
+    }
+
+    if let Some(motor_controllers) = &mut *motor_controllers {
+        for (motor, motor_config) in motor_controllers.iter_mut().zip(&config.motors) {
+            motor.configure(motor_config);
+        }
+    }
+
+    motor_map::set(config.motor_map);
+    current::set_gains(&config.current_gain);
+    current::set_filter_time(&config.current_filter);
+    temperature::set_limit(&config.thermal_limit);
+    control_loop::set_rate(config.control_loop_rate);
+    ACTIVE.lock(|active| *active.borrow_mut() = config);
+
+    Ok(())
+}
+
+/// Persists the config in use to flash
+pub async fn commit() -> Result<(), c2h::Error> {
+    with_store(|store| store.write(&get())).await
+}
+
+/// Erases the persisted config and applies the defaults
+pub async fn factory_reset() -> Result<(), c2h::Error> {
+    with_store(Store::erase).await?;
+    apply(DeviceConfig::DEFAULT).await;
+
+    Ok(())
+}
+
+/// Runs a flash operation, refused while a motor is armed since flash operations stall every task
+async fn with_store(
+    op: impl FnOnce(&mut Store) -> Result<(), flash::Error>,
+) -> Result<(), c2h::Error> {
+    // Held until the operation finishes so no motor gets armed in the meantime
+    let motor_controllers = MOTOR_CONTROLLERS.lock().await;
+    if motor_controllers
+        .iter()
+        .flatten()
+        .any(MotorController::is_driven)
+    {
+        return Err(c2h::Error::Armed);
+    }
+
+    let mut store = STORE.lock().await;
+    let Some(store) = &mut *store else {
+        return Err(c2h::Error::FlashError);
+    };
+
+    op(store).map_err(|err| {
+        warn!("Config flash operation failed: {}", err);
+        c2h::Error::FlashError
+    })
+}
+
+impl Store {
+    fn write(&mut self, config: &DeviceConfig) -> Result<(), flash::Error> {
+        let sequence = self.newest.map_or(0, |(_, sequence)| sequence + 1);
+        let mut slot = self.newest.map_or(0, |(slot, _)| (slot + 1) % SLOTS);
+
+        // Slots left dirty by an interrupted write can only be reused once their sector is erased
+        if slot % SLOTS_PER_SECTOR != 0 && !self.is_blank(slot)? {
+            slot = (slot / SLOTS_PER_SECTOR + 1) * SLOTS_PER_SECTOR % SLOTS;
+        }
+
+        let mut block = [0xFF; SLOT_SIZE];
+        let len = unwrap!(postcard::to_slice(
+            config,
+            &mut block[HEADER_SIZE..SLOT_SIZE - CRC_SIZE]
+        ))
+        .len();
+
+        block[0..4].copy_from_slice(&MAGIC);
+        block[4..6].copy_from_slice(&LAYOUT_VERSION.to_le_bytes());
+        block[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        block[8..12].copy_from_slice(&sequence.to_le_bytes());
+
+        let crc = CRC.checksum(&block[..HEADER_SIZE + len]);
+        block[HEADER_SIZE + len..][..CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+        let offset = slot_offset(slot);
+        if slot % SLOTS_PER_SECTOR == 0 {
+            hardware_watchdog::feed();
+            self.flash.erase(offset, offset + SECTOR_SIZE)?;
+        }
+        self.flash.write(offset, &block)?;
+
+        self.newest = Some((slot, sequence));
+        info!("Committed config to slot {}", slot);
+
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), flash::Error> {
+        // One sector at a time, erasing the whole region could outlast the hardware watchdog
+        for sector in 0..REGION_SECTORS {
+            let offset = REGION_START + sector * SECTOR_SIZE;
+
+            hardware_watchdog::feed();
+            self.flash.erase(offset, offset + SECTOR_SIZE)?;
+        }
+        self.newest = None;
+        info!("Erased config");
+
+        Ok(())
+    }
+
+    fn is_blank(&mut self, slot: u32) -> Result<bool, flash::Error> {
+        let mut block = [0; SLOT_SIZE];
+        self.flash.read(slot_offset(slot), &mut block)?;
+
+        Ok(block.iter().all(|&byte| byte == 0xFF))
+    }
+}
+
+fn slot_offset(slot: u32) -> u32 {
+    REGION_START + slot * SLOT_SIZE as u32
+}
+
+/// Returns the sequence number of a block with a valid CRC, along with its config when the block
+/// has the current layout
+fn parse_block(block: &[u8; SLOT_SIZE]) -> Option<(u32, Option<DeviceConfig>)> {
+    if block[0..4] != MAGIC {
+        return None;
+    }
+
+    let version = u16::from_le_bytes([block[4], block[5]]);
+    let len = u16::from_le_bytes([block[6], block[7]]) as usize;
+    let sequence = u32::from_le_bytes([block[8], block[9], block[10], block[11]]);
+
+    if HEADER_SIZE + len + CRC_SIZE > SLOT_SIZE {
+        return None;
+    }
+
+    let (data, rest) = block.split_at(HEADER_SIZE + len);
+    let crc = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+    if CRC.checksum(data) != crc {
+        return None;
+    }
+
+    let config = (version == LAYOUT_VERSION)
+        .then(|| postcard::from_bytes::<DeviceConfig>(&data[HEADER_SIZE..]).ok())
+        .flatten()
+        .filter(DeviceConfig::is_valid);
+
+    Some((sequence, config))
+}
@@ -0,0 +1,118 @@
+//! Fixed rate loop applying the motor controller setpoints to the motor drivers
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use defmt::warn;
+use embassy_time::{Duration, Instant, Timer};
+use interface::{
+    DeviceConfig, Motors,
+    c2h::{self, MotorFault},
+};
+use portable_atomic::AtomicU32;
+
+use crate::{
+    current,
+    hardware_watchdog::{self, Task},
+    motor_controller::{Drv8874, MOTOR_CONTROLLERS},
+    motor_map,
+    serial::handler::broadcast_packet,
+};
+
+static RATE_HZ: AtomicU16 = AtomicU16::new(DeviceConfig::DEFAULT.control_loop_rate);
+
+static TICKS: AtomicU32 = AtomicU32::new(0);
+static OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static MAX_TICK_US: AtomicU32 = AtomicU32::new(0);
+
+/// Sets how often the control loop runs, clamped to [`DeviceConfig::CONTROL_LOOP_RATES`]
+///
+/// Returns the rate that was applied
+pub fn set_rate(rate_hz: u16) -> u16 {
+    let rates = DeviceConfig::CONTROL_LOOP_RATES;
+    let rate_hz = rate_hz.clamp(*rates.start(), *rates.end());
+    RATE_HZ.store(rate_hz, Ordering::Relaxed);
+    rate_hz
+}
+
+pub fn stats() -> c2h::ControlLoopStats {
+    c2h::ControlLoopStats {
+        rate_hz: RATE_HZ.load(Ordering::Relaxed),
+        ticks: TICKS.load(Ordering::Relaxed),
+        overruns: OVERRUNS.load(Ordering::Relaxed),
+        max_tick_us: MAX_TICK_US.load(Ordering::Relaxed),
+    }
+}
+
+pub fn reset_stats() {
+    TICKS.store(0, Ordering::Relaxed);
+    OVERRUNS.store(0, Ordering::Relaxed);
+    MAX_TICK_US.store(0, Ordering::Relaxed);
+}
+
+/// Runs the control loop, `drivers` are indexed by physical output
+#[embassy_executor::task]
+pub async fn run_control_loop(mut drivers: [Drv8874; 4]) {
+    let mut deadline = Instant::now();
+
+    loop {
+        let start = Instant::now();
+        tick(&mut drivers, start).await;
+        let end = Instant::now();
+
+        hardware_watchdog::check_in(Task::ControlLoop);
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        MAX_TICK_US.fetch_max((end - start).as_micros() as u32, Ordering::Relaxed);
+
+        deadline += Duration::from_hz(RATE_HZ.load(Ordering::Relaxed) as u64);
+        if end > deadline {
+            OVERRUNS.fetch_add(1, Ordering::Relaxed);
+
+            // Skip the missed ticks instead of trying to catch up
+            deadline = end;
+        }
+
+        Timer::at(deadline).await;
+    }
+}
+
+async fn tick(drivers: &mut [Drv8874; 4], now: Instant) {
+    let mut tripped = [false; 4];
+
+    {
+        let mut motor_controllers = MOTOR_CONTROLLERS.lock().await;
+        let Some(motor_controllers) = &mut *motor_controllers else {
+            return;
+        };
+
+        let motor_map = motor_map::get();
+
+        for (motor_id, motor) in motor_controllers.iter_mut().enumerate() {
+            let output = motor_map.outputs[motor_id] as usize;
+            let amps = current::ADC_WATCHES[output].try_get().unwrap_or(-1.0);
+
+            tripped[motor_id] = motor.tick(amps, now);
+
+            let speed = if motor_map
+                .inverted
+                .contains(Motors::from_bits_truncate(1 << motor_id))
+            {
+                -motor.applied_speed()
+            } else {
+                motor.applied_speed()
+            };
+            drivers[output].apply(motor.is_driven(), speed);
+        }
+    }
+
+    for (motor_id, _) in tripped.iter().enumerate().filter(|(_, tripped)| **tripped) {
+        warn!("Motor {} overcurrent trip", motor_id);
+
+        broadcast_packet(
+            c2h::MotorError {
+                motor_id: motor_id as u8,
+                fault: MotorFault::Overcurrent,
+            }
+            .into(),
+        );
+    }
+}
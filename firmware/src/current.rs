@@ -1,22 +1,232 @@
-use defmt::{info, unwrap};
+use core::cell::Cell;
+
+use defmt::{info, unwrap, warn};
 use embassy_executor::Spawner;
 use embassy_rp::{
     adc::{Adc, Channel, Config},
     gpio::Pull,
     peripherals::*,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, watch::Watch};
-use embassy_time::{Duration, Ticker};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+    signal::Signal,
+    watch::Watch,
+};
+use embassy_time::{Duration, Instant, Ticker};
+use interface::{Charge, CurrentKind, DeviceConfig, Interval, c2h};
+
+use crate::{
+    Irqs,
+    hardware_watchdog::{self, Task},
+    motor_controller::MOTOR_CONTROLLERS,
+    motor_map,
+    serial::handler::SERIAL_CTXS,
+    temperature,
+};
 
-use crate::Irqs;
+const NUM_CHANNELS: usize = 4;
+const FREQUENCY: usize = 1000;
 
 #[expect(
     clippy::declare_interior_mutable_const,
     reason = "Used as template to init array of statics"
 )]
 const NEW_WATCH: Watch<CriticalSectionRawMutex, f32, 4> = Watch::new();
+/// Latest current of every physical output, see [`motor_current`] for logical motors
 pub static ADC_WATCHES: [Watch<CriticalSectionRawMutex, f32, 4>; 4] = [NEW_WATCH; 4];
 
+/// Raw reading at zero current of every physical output, measured at boot and on request
+static OFFSETS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[u16; 4]>> =
+    blocking_mutex::Mutex::new(Cell::new([0; 4]));
+/// Scale of every physical output, see [`DeviceConfig::current_gain`]
+static GAINS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[f32; 4]>> =
+    blocking_mutex::Mutex::new(Cell::new([1.0; 4]));
+
+/// Low pass filtered current of every physical output, `None` until the first reading
+static FILTERED: blocking_mutex::Mutex<
+    CriticalSectionRawMutex,
+    Cell<Option<[FilteredCurrent; 4]>>,
+> = blocking_mutex::Mutex::new(Cell::new(None));
+/// Weight of a new sample in the filters
+static FILTER_ALPHA: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<f32>> =
+    blocking_mutex::Mutex::new(Cell::new(1.0));
+
+#[derive(Clone, Copy)]
+struct FilteredCurrent {
+    average: f32,
+    mean_square: f32,
+}
+
+impl FilteredCurrent {
+    fn new(amps: f32) -> Self {
+        Self {
+            average: amps,
+            mean_square: amps * amps,
+        }
+    }
+
+    fn update(&mut self, amps: f32, alpha: f32) {
+        self.average += alpha * (amps - self.average);
+        self.mean_square += alpha * (amps * amps - self.mean_square);
+    }
+}
+
+/// Charge drawn by every logical motor in picocoulombs (microamp microseconds), kept per motor so
+/// remapping does not move it to another motor
+static CHARGE: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[u64; 4]>> =
+    blocking_mutex::Mutex::new(Cell::new([0; 4]));
+const PICOCOULOMBS_PER_MICROAMP_HOUR: u64 = 3_600_000_000;
+
+/// Highest current of every physical output since it was last taken, kept by every interface so
+/// their reports do not reset each other
+pub struct PeakCurrent(blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[f32; 4]>>);
+
+impl PeakCurrent {
+    pub const fn new() -> Self {
+        Self(blocking_mutex::Mutex::new(Cell::new([-1.0; 4])))
+    }
+
+    fn record(&self, amps: &[f32; 4]) {
+        self.0.lock(|cell| {
+            let mut peaks = cell.get();
+            for (peak, amps) in peaks.iter_mut().zip(amps) {
+                *peak = peak.max(*amps);
+            }
+
+            cell.set(peaks);
+        });
+    }
+
+    /// Peak of `motor_id` since the last call, negative when there was no reading since
+    pub fn take(&self, motor_id: u8) -> f32 {
+        let output = motor_map::output(motor_id);
+
+        self.0.lock(|cell| {
+            let mut peaks = cell.get();
+            let peak = core::mem::replace(&mut peaks[output], -1.0);
+            cell.set(peaks);
+
+            peak
+        })
+    }
+}
+
+impl Default for PeakCurrent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Readings averaged to find the zero offsets
+const CALIBRATION_SAMPLES: u32 = 256;
+
+static CALIBRATION_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static CALIBRATION_RESULT: Signal<CriticalSectionRawMutex, Option<[u16; 4]>> = Signal::new();
+/// Serializes [`calibrate`] so every caller receives its own result
+static CALIBRATION_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Latest current of `motor_id` in amps, negative when there is no reading yet
+pub fn motor_current(motor_id: u8) -> f32 {
+    ADC_WATCHES[motor_map::output(motor_id)]
+        .try_get()
+        .unwrap_or(-1.0)
+}
+
+/// Low pass filtered current of `motor_id` in amps, negative when there is no reading yet
+pub fn average_current(motor_id: u8) -> f32 {
+    filtered(motor_id).map_or(-1.0, |filtered| filtered.average)
+}
+
+/// RMS current of `motor_id` in amps, negative when there is no reading yet
+pub fn rms_current(motor_id: u8) -> f32 {
+    filtered(motor_id).map_or(-1.0, |filtered| libm::sqrtf(filtered.mean_square))
+}
+
+/// Current of `motor_id` in amps measured as `kind`, `peak` is reset when read
+pub fn read(motor_id: u8, kind: CurrentKind, peak: &PeakCurrent) -> f32 {
+    match kind {
+        CurrentKind::Instantaneous => motor_current(motor_id),
+        CurrentKind::Average => average_current(motor_id),
+        CurrentKind::Rms => rms_current(motor_id),
+        CurrentKind::Peak => peak.take(motor_id),
+    }
+}
+
+/// Charge drawn by `motor_id` since its counter was last reset
+pub fn charge(motor_id: u8) -> Charge {
+    let picocoulombs = CHARGE.lock(|cell| cell.get()[motor_id as usize]);
+
+    Charge(u32::try_from(picocoulombs / PICOCOULOMBS_PER_MICROAMP_HOUR).unwrap_or(u32::MAX))
+}
+
+pub fn reset_charge(motor_id: u8) {
+    CHARGE.lock(|cell| {
+        let mut charge = cell.get();
+        charge[motor_id as usize] = 0;
+        cell.set(charge);
+    });
+}
+
+fn filtered(motor_id: u8) -> Option<FilteredCurrent> {
+    let output = motor_map::output(motor_id);
+
+    FILTERED
+        .lock(|cell| cell.get())
+        .map(|filtered| filtered[output])
+}
+
+/// Sets the time constant of the average and RMS filters
+pub fn set_filter_time(time_constant: &Interval) {
+    let sample_period = 1.0 / FREQUENCY as f32;
+    let time_constant = time_constant.as_duration().as_micros() as f32 / 1e6;
+
+    FILTER_ALPHA.lock(|cell| cell.set(sample_period / (time_constant + sample_period)));
+}
+
+pub fn set_gains(gains: &[u16; 4]) {
+    let gains = gains.map(|gain| gain as f32 / DeviceConfig::UNITY_GAIN as f32);
+    GAINS.lock(|cell| cell.set(gains));
+}
+
+/// Measures the zero offset of every output, refused while a motor is armed
+///
+/// The new offsets are used until the next calibration, they are not persisted
+pub async fn calibrate() -> Result<[u16; 4], c2h::Error> {
+    let _lock = CALIBRATION_LOCK.lock().await;
+
+    CALIBRATION_RESULT.reset();
+    CALIBRATION_REQUEST.signal(());
+
+    CALIBRATION_RESULT.wait().await.ok_or(c2h::Error::Armed)
+}
+
+/// Accumulates raw readings while no motor is armed
+struct ZeroCalibration {
+    sums: [u32; 4],
+    samples: u32,
+}
+
+impl ZeroCalibration {
+    fn new() -> Self {
+        Self {
+            sums: [0; 4],
+            samples: 0,
+        }
+    }
+
+    /// Returns the offsets once enough readings were taken
+    fn add(&mut self, raw: [u16; 4]) -> Option<[u16; 4]> {
+        for (sum, raw) in self.sums.iter_mut().zip(raw) {
+            *sum += raw as u32;
+        }
+        self.samples += 1;
+
+        (self.samples == CALIBRATION_SAMPLES)
+            .then(|| self.sums.map(|sum| (sum / self.samples) as u16))
+    }
+}
+
 #[embassy_executor::task]
 pub async fn start_adc_dma(
     spawner: Spawner,
@@ -26,43 +236,138 @@ pub async fn start_adc_dma(
     pin_27: PIN_27,
     pin_28: PIN_28,
     pin_29: PIN_29,
+    temp_sensor: ADC_TEMP_SENSOR,
 ) {
     let mut adc = Adc::new(adc, Irqs, Config::default());
-    let mut pins = [
+    let mut channels = [
         Channel::new_pin(pin_26, Pull::None),
         Channel::new_pin(pin_27, Pull::None),
         Channel::new_pin(pin_28, Pull::None),
         Channel::new_pin(pin_29, Pull::None),
+        Channel::new_temp_sensor(temp_sensor),
     ];
+    // Sampled after the current sense pins
+    const TEMP_SENSOR_IDX: usize = NUM_CHANNELS;
 
-    // mot 0 -> idx 2
-    // mot 1 -> idx 0
-    // mot 2 -> idx 3
-    // mot 3 -> idx 1
+    // output 0 -> idx 2
+    // output 1 -> idx 0
+    // output 2 -> idx 3
+    // output 3 -> idx 1
     const PIN_MAP: [u8; 4] = [2, 0, 3, 1];
 
-    const NUM_CHANNELS: usize = 4;
-    const FREQUENCY: usize = 1000;
-
     unwrap!(spawner.spawn(log_adc_readings()));
 
+    // Motors are disarmed at boot, which makes it a good time to find the zero offsets
+    let mut zero_calibration = Some(ZeroCalibration::new());
+    let mut requested = false;
+    let mut last_reading: Option<Instant> = None;
+
     loop {
-        let mut buf = [0_u16; NUM_CHANNELS];
-        let div = (48_000_000.0 / (FREQUENCY * NUM_CHANNELS) as f64) as u16; // 100kHz sample rate (48Mhz / (100kHz * 4ch) - 1)
+        let mut buf = [0_u16; NUM_CHANNELS + 1];
+        let div = (48_000_000.0 / (FREQUENCY * buf.len()) as f64) as u16; // 100kHz sample rate (48Mhz / (100kHz * 4ch) - 1)
 
-        adc.read_many_multichannel(&mut pins, &mut buf, div, &mut dma)
+        adc.read_many_multichannel(&mut channels, &mut buf, div, &mut dma)
             .await
             .unwrap();
+        hardware_watchdog::check_in(Task::Adc);
+
+        let now = Instant::now();
+        let raw = PIN_MAP.map(|idx| buf[idx as usize]);
+        temperature::record(buf[TEMP_SENSOR_IDX]).await;
+        let offsets = OFFSETS.lock(|cell| cell.get());
+        let gains = GAINS.lock(|cell| cell.get());
 
-        for (idx, watch) in ADC_WATCHES.iter().enumerate() {
-            let voltage = buf[PIN_MAP[idx] as usize] as f32 / 4095.0 * 3.0;
-            let amperage = voltage / 2.2e3 / 4.5e-4;
+        let mut amps = [0.0; 4];
+        for (((amps, raw), offset), gain) in amps.iter_mut().zip(raw).zip(offsets).zip(gains) {
+            let counts = (raw as f32 - offset as f32).max(0.0);
+            let voltage = counts / 4095.0 * 3.0;
+            *amps = voltage / 2.2e3 / 4.5e-4 * gain;
+        }
+
+        for (watch, amps) in ADC_WATCHES.iter().zip(amps) {
+            watch.sender().send(amps);
+        }
+
+        let alpha = FILTER_ALPHA.lock(|cell| cell.get());
+        FILTERED.lock(|cell| {
+            let filtered = match cell.get() {
+                Some(mut filtered) => {
+                    for (filtered, amps) in filtered.iter_mut().zip(amps) {
+                        filtered.update(amps, alpha);
+                    }
+
+                    filtered
+                }
+                None => amps.map(FilteredCurrent::new),
+            };
+
+            cell.set(Some(filtered));
+        });
 
-            watch.sender().send(amperage);
+        for ctx in SERIAL_CTXS {
+            ctx.peak_current.record(&amps);
+        }
+
+        // Integrated over the measured time between readings instead of the nominal FREQUENCY
+        if let Some(last_reading) = last_reading {
+            let micros = (now - last_reading).as_micros();
+            CHARGE.lock(|cell| {
+                let mut charge = cell.get();
+                for (output, amps) in amps.iter().enumerate() {
+                    // Rounded, truncating every reading would count systematically low
+                    let microamps = (amps * 1e6 + 0.5) as u64;
+                    let charge = &mut charge[motor_map::motor_id(output) as usize];
+                    *charge = charge.saturating_add(microamps.saturating_mul(micros));
+                }
+
+                cell.set(charge);
+            });
+        }
+        last_reading = Some(now);
+
+        if CALIBRATION_REQUEST.try_take().is_some() {
+            zero_calibration = Some(ZeroCalibration::new());
+            requested = true;
+        }
+
+        let Some(calibration) = &mut zero_calibration else {
+            continue;
+        };
+
+        // Motor current would end up in the offsets
+        if is_any_armed().await {
+            warn!("Current calibration aborted, a motor is armed");
+
+            zero_calibration = None;
+            if core::mem::take(&mut requested) {
+                CALIBRATION_RESULT.signal(None);
+            }
+
+            continue;
+        }
+
+        if let Some(offsets) = calibration.add(raw) {
+            info!("Current calibrated, zero offsets: {}", offsets);
+
+            OFFSETS.lock(|cell| cell.set(offsets));
+
+            zero_calibration = None;
+            if core::mem::take(&mut requested) {
+                CALIBRATION_RESULT.signal(Some(offsets));
+            }
         }
     }
 }
 
+async fn is_any_armed() -> bool {
+    let motor_controllers = MOTOR_CONTROLLERS.lock().await;
+
+    motor_controllers
+        .iter()
+        .flatten()
+        .any(|motor| motor.is_driven())
+}
+
 #[embassy_executor::task]
 async fn log_adc_readings() {
     let mut ticker = Ticker::every(Duration::from_secs(1));
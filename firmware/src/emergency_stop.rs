@@ -0,0 +1,105 @@
+//! Latching emergency stop, unlike disarming it can not be undone by arming again
+//!
+//! The latch is kept in the watchdog scratch registers so it survives soft resets, including the
+//! ones caused by the hardware watchdog
+
+use core::cell::Cell;
+
+use defmt::{Debug2Format, info, warn};
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use interface::{Port, c2h};
+use rand_core::RngCore;
+
+use crate::{
+    hardware_watchdog, motor_controller::MOTOR_CONTROLLERS, safety_watchdog,
+    serial::handler::broadcast_packet,
+};
+
+const SCRATCH_LATCH: usize = 0;
+const SCRATCH_TOKEN: usize = 1;
+/// Written to the latch register while latched, anything else counts as released
+const LATCHED: u32 = u32::from_le_bytes(*b"ESTP");
+
+/// Token of the latched emergency stop
+static TOKEN: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<u32>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+/// Restores an emergency stop latched before the last reset, call after [`hardware_watchdog::init`]
+pub fn init() {
+    if hardware_watchdog::scratch(SCRATCH_LATCH) != LATCHED {
+        return;
+    }
+
+    let token = hardware_watchdog::scratch(SCRATCH_TOKEN);
+    TOKEN.lock(|cell| cell.set(Some(token)));
+    warn!("Emergency stop latched before the reset is still active");
+}
+
+/// Token needed to clear the emergency stop, `None` when not latched
+pub fn token() -> Option<u32> {
+    TOKEN.lock(|cell| cell.get())
+}
+
+pub fn is_latched() -> bool {
+    token().is_some()
+}
+
+/// Disarms every motor before returning and latches until cleared with the token
+pub async fn trigger(source: Port) {
+    let token = {
+        // Latched under the lock so the safety watch dog can not arm the motors in between
+        let mut motor_controllers = MOTOR_CONTROLLERS.lock().await;
+
+        let token = TOKEN.lock(|cell| {
+            let token = cell.get().unwrap_or_else(|| RoscRng.next_u32());
+            cell.set(Some(token));
+
+            token
+        });
+        safety_watchdog::disarm(&mut motor_controllers);
+
+        token
+    };
+
+    hardware_watchdog::set_scratch(SCRATCH_TOKEN, token);
+    hardware_watchdog::set_scratch(SCRATCH_LATCH, LATCHED);
+    warn!("Emergency stop from {}", Debug2Format(&source));
+
+    broadcast_packet(
+        c2h::EmergencyStopEvent {
+            latched: true,
+            token,
+            source,
+        }
+        .into(),
+    );
+}
+
+/// Releases the emergency stop when `token` matches, returns whether it was latched
+pub fn clear(token: u32, source: Port) -> Result<bool, c2h::Error> {
+    let released = TOKEN.lock(|cell| match cell.get() {
+        None => Ok(false),
+        Some(latched) if latched == token => {
+            cell.set(None);
+            Ok(true)
+        }
+        Some(_) => Err(c2h::Error::WrongToken),
+    })?;
+
+    if released {
+        hardware_watchdog::set_scratch(SCRATCH_LATCH, 0);
+        info!("Emergency stop cleared from {}", Debug2Format(&source));
+
+        broadcast_packet(
+            c2h::EmergencyStopEvent {
+                latched: false,
+                token,
+                source,
+            }
+            .into(),
+        );
+    }
+
+    Ok(released)
+}
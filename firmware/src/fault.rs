@@ -0,0 +1,79 @@
+//! Watches the nFAULT output of every motor driver and notifies the hosts of changes
+//!
+//! The asserted state follows the nFAULT pin of each physical output, while fault counts and
+//! events belong to the logical motor driven by the output when the fault changed, see
+//! [`crate::motor_map`]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::warn;
+use embassy_futures::select::{Either, select};
+use embassy_rp::gpio::Input;
+use embassy_time::{Duration, Instant, Timer};
+use interface::c2h;
+use portable_atomic::AtomicU32;
+
+use crate::{motor_map, serial::handler::broadcast_packet};
+
+/// Indexed by physical output
+static FAULT_ASSERTED: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+/// Indexed by logical motor id, so remapping does not move them to another motor
+static FAULT_COUNTS: [AtomicU32; 4] = [const { AtomicU32::new(0) }; 4];
+
+/// How often the fault pin level is re-checked in case an edge slipped in before the wait was armed
+const RECHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+pub fn is_fault(motor_id: u8) -> bool {
+    FAULT_ASSERTED[motor_map::output(motor_id)].load(Ordering::Relaxed)
+}
+
+/// Number of times the driver of `motor_id` asserted nFAULT since boot
+pub fn fault_count(motor_id: u8) -> u32 {
+    FAULT_COUNTS[motor_id as usize].load(Ordering::Relaxed)
+}
+
+#[embassy_executor::task(pool_size = 4)]
+pub async fn watch_fault(output: usize, mut fault: Input<'static>) {
+    let mut asserted = false;
+
+    loop {
+        // Fault pin is active low
+        if fault.is_low() != asserted {
+            asserted = !asserted;
+            record_fault_change(output, asserted);
+        }
+
+        let edge = select(fault.wait_for_any_edge(), Timer::after(RECHECK_INTERVAL)).await;
+
+        // The pin toggled and came back before it could be sampled
+        if let Either::First(()) = edge {
+            if fault.is_low() == asserted {
+                record_fault_change(output, !asserted);
+                record_fault_change(output, asserted);
+            }
+        }
+    }
+}
+
+fn record_fault_change(output: usize, asserted: bool) {
+    let motor_id = motor_map::motor_id(output);
+    FAULT_ASSERTED[output].store(asserted, Ordering::Relaxed);
+
+    let fault_count = if asserted {
+        warn!("Motor {} driver fault asserted", motor_id);
+        FAULT_COUNTS[motor_id as usize].fetch_add(1, Ordering::Relaxed) + 1
+    } else {
+        warn!("Motor {} driver fault cleared", motor_id);
+        FAULT_COUNTS[motor_id as usize].load(Ordering::Relaxed)
+    };
+
+    broadcast_packet(
+        c2h::FaultEvent {
+            motor_id,
+            asserted,
+            timestamp_us: Instant::now().as_micros(),
+            fault_count,
+        }
+        .into(),
+    );
+}
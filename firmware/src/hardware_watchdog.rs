@@ -0,0 +1,184 @@
+//! RP2040 hardware watchdog, resets the chip when the critical tasks stop making progress
+//!
+//! Unlike [`crate::safety_watchdog`], which disarms the motors when the host goes quiet, this
+//! catches the firmware itself hanging, for example a task holding the motor controllers lock
+//! while blocked on a full packet queue. The motors come up disarmed after the reset
+
+use core::cell::{Cell, RefCell};
+
+use defmt::{info, warn};
+use embassy_rp::{
+    peripherals::WATCHDOG,
+    watchdog::{self, Watchdog},
+};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Ticker};
+use interface::c2h::ResetReason;
+use portable_atomic::{AtomicU8, Ordering};
+
+/// Longer than a control loop tick at its slowest rate
+const TIMEOUT: Duration = Duration::from_millis(1500);
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tasks that have to check in for the watchdog to be fed
+#[derive(Clone, Copy)]
+pub enum Task {
+    ControlLoop = 1 << 0,
+    Adc = 1 << 1,
+    SafetyWatchdog = 1 << 2,
+    UsbRead = 1 << 3,
+    UartRead = 1 << 4,
+}
+
+/// Tasks that run periodically and always have to check in, the others wait on hosts and events
+/// and only have to while [`busy`]
+const PERIODIC_TASKS: u8 = Task::ControlLoop as u8 | Task::Adc as u8;
+
+/// Tasks that checked in since the watchdog was last fed
+static CHECK_INS: AtomicU8 = AtomicU8::new(0);
+/// Tasks in the middle of handling an event
+static BUSY: AtomicU8 = AtomicU8::new(0);
+
+/// Kept in a static so its scratch registers stay reachable once it is running
+static WATCHDOG: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Option<Watchdog>>> =
+    blocking_mutex::Mutex::new(RefCell::new(None));
+
+static RESET_REASON: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<ResetReason>> =
+    blocking_mutex::Mutex::new(Cell::new(ResetReason::PowerOn));
+
+/// Takes the watchdog peripheral and records why the chip was reset, does not start the watchdog
+pub fn init(watchdog: WATCHDOG) -> ResetReason {
+    let mut watchdog = Watchdog::new(watchdog);
+
+    let reset_reason = match watchdog.reset_reason() {
+        None => ResetReason::PowerOn,
+        Some(watchdog::ResetReason::TimedOut) => ResetReason::WatchdogTimeout,
+        Some(watchdog::ResetReason::Forced) => ResetReason::Forced,
+    };
+
+    if reset_reason == ResetReason::WatchdogTimeout {
+        warn!("Reset by the hardware watchdog, the firmware stopped responding");
+    }
+
+    // Halting in a debugger must not reset the chip
+    watchdog.pause_on_debug(true);
+
+    RESET_REASON.lock(|cell| cell.set(reset_reason));
+    WATCHDOG.lock(|cell| *cell.borrow_mut() = Some(watchdog));
+
+    reset_reason
+}
+
+pub fn reset_reason() -> ResetReason {
+    RESET_REASON.lock(|cell| cell.get())
+}
+
+/// Reports that `task` is still making progress
+pub fn check_in(task: Task) {
+    CHECK_INS.fetch_or(task as u8, Ordering::Relaxed);
+}
+
+/// Marks `task` as handling an event until the guard is dropped, which checks it in
+///
+/// The watchdog starves when a task stays busy for too long, for example a packet handler waiting
+/// on a lock that is never released
+pub fn busy(task: Task) -> Busy {
+    BUSY.fetch_or(task as u8, Ordering::Relaxed);
+    Busy(task)
+}
+
+pub struct Busy(Task);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.fetch_and(!(self.0 as u8), Ordering::Relaxed);
+        check_in(self.0);
+    }
+}
+
+/// Pauses a [`busy`] `task` until the guard is dropped, for waits that are not a hang such as
+/// backpressure from a host
+pub fn idle(task: Task) -> Idle {
+    let was_busy = BUSY.fetch_and(!(task as u8), Ordering::Relaxed) & task as u8 != 0;
+    Idle { task, was_busy }
+}
+
+pub struct Idle {
+    task: Task,
+    was_busy: bool,
+}
+
+impl Drop for Idle {
+    fn drop(&mut self) {
+        if self.was_busy {
+            BUSY.fetch_or(self.task as u8, Ordering::Relaxed);
+            check_in(self.task);
+        }
+    }
+}
+
+/// Feeds the watchdog without checking the tasks, only for blocking operations of bounded length
+/// such as flash erases
+pub fn feed() {
+    WATCHDOG.lock(|cell| {
+        if let Some(watchdog) = &mut *cell.borrow_mut() {
+            watchdog.feed();
+        }
+    });
+}
+
+/// Scratch registers survive every reset apart from power on, the boot ROM uses registers 4 to 7
+pub fn scratch(index: usize) -> u32 {
+    WATCHDOG.lock(|cell| {
+        cell.borrow_mut()
+            .as_mut()
+            .map_or(0, |watchdog| watchdog.get_scratch(index))
+    })
+}
+
+pub fn set_scratch(index: usize, value: u32) {
+    WATCHDOG.lock(|cell| {
+        if let Some(watchdog) = &mut *cell.borrow_mut() {
+            watchdog.set_scratch(index, value);
+        }
+    });
+}
+
+/// Starts the watchdog and feeds it while every task keeps checking in
+#[embassy_executor::task]
+pub async fn run_hardware_watchdog() {
+    WATCHDOG.lock(|cell| {
+        if let Some(watchdog) = &mut *cell.borrow_mut() {
+            watchdog.start(TIMEOUT);
+        }
+    });
+    info!("Hardware watchdog started");
+
+    let mut ticker = Ticker::every(CHECK_INTERVAL);
+    let mut last_fed = Instant::now();
+    let mut warned = false;
+
+    loop {
+        ticker.next().await;
+
+        let required = PERIODIC_TASKS | BUSY.load(Ordering::Relaxed);
+        let check_ins = CHECK_INS.load(Ordering::Relaxed);
+        if check_ins & required != required {
+            // Slow control loop rates miss some checks, only warn once a reset is getting close
+            if !warned && last_fed.elapsed() > TIMEOUT / 2 {
+                warn!(
+                    "Hardware watchdog starved, tasks checked in: {:b}, required: {:b}",
+                    check_ins, required
+                );
+                warned = true;
+            }
+
+            continue;
+        }
+
+        CHECK_INS.store(0, Ordering::Relaxed);
+        feed();
+        last_fed = Instant::now();
+        warned = false;
+    }
+}
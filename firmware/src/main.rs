@@ -5,17 +5,27 @@
 #![no_std]
 #![no_main]
 
+pub mod build_info;
+pub mod config;
+pub mod control_loop;
 pub mod current;
+pub mod emergency_stop;
+pub mod fault;
+pub mod hardware_watchdog;
 pub mod motor_controller;
+pub mod motor_map;
+pub mod ownership;
 pub mod safety_watchdog;
 pub mod serial;
+pub mod temperature;
 
-use defmt::{info, unwrap};
+use defmt::{Debug2Format, info, unwrap};
 use embassy_executor::Spawner;
+use embassy_rp::gpio::{Input, Pull};
 use embassy_rp::peripherals::{I2C1, UART0, USB};
 use embassy_rp::{adc, uart, usb};
 use embassy_rp::{bind_interrupts, i2c};
-use motor_controller::Drv8874;
+use motor_controller::{Drv8874, MotorController};
 use {defmt_rtt as _, panic_probe as _};
 
 bind_interrupts!(struct Irqs {
@@ -33,28 +43,86 @@ bind_interrupts!(struct Irqs {
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    info!("Starting DC Motor controller");
+    info!(
+        "Starting DC Motor controller v{}.{}.{} on board {}",
+        build_info::VERSION.major,
+        build_info::VERSION.minor,
+        build_info::VERSION.patch,
+        build_info::BOARD
+    );
 
     let p = embassy_rp::init(Default::default());
 
+    let reset_reason = hardware_watchdog::init(p.WATCHDOG);
+    info!("Reset reason: {}", Debug2Format(&reset_reason));
+    emergency_stop::init();
+
+    let device_config = config::init(p.FLASH).await;
+    info!("Device name: {}", device_config.device_name.as_str());
+
     // Configure global for motor controllers
     {
         let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
 
-        // TODO: Gate this order behind feature flag
-        *motor_controllers = Some([
-            Drv8874::new(0, p.PWM_SLICE3, p.PIN_6, p.PIN_7, p.PIN_8, p.PIN_9),
-            Drv8874::new(1, p.PWM_SLICE7, p.PIN_14, p.PIN_15, p.PIN_16, p.PIN_17),
-            Drv8874::new(2, p.PWM_SLICE1, p.PIN_2, p.PIN_3, p.PIN_4, p.PIN_5),
-            Drv8874::new(3, p.PWM_SLICE5, p.PIN_10, p.PIN_11, p.PIN_12, p.PIN_13),
-        ]);
+        let mut controllers = [
+            MotorController::new(0),
+            MotorController::new(1),
+            MotorController::new(2),
+            MotorController::new(3),
+        ];
+        for (motor, motor_config) in controllers.iter_mut().zip(&device_config.motors) {
+            motor.configure(motor_config);
+        }
+
+        *motor_controllers = Some(controllers);
     }
+    motor_map::set(device_config.motor_map);
+    current::set_gains(&device_config.current_gain);
+    current::set_filter_time(&device_config.current_filter);
+    temperature::set_limit(&device_config.thermal_limit);
+    control_loop::set_rate(device_config.control_loop_rate);
+
+    // Physical outputs in board order, logical motors are mapped onto them by `motor_map`
+    unwrap!(spawner.spawn(control_loop::run_control_loop([
+        Drv8874::new(p.PWM_SLICE3, p.PIN_6, p.PIN_7, p.PIN_8),
+        Drv8874::new(p.PWM_SLICE7, p.PIN_14, p.PIN_15, p.PIN_16),
+        Drv8874::new(p.PWM_SLICE1, p.PIN_2, p.PIN_3, p.PIN_4),
+        Drv8874::new(p.PWM_SLICE5, p.PIN_10, p.PIN_11, p.PIN_12),
+    ])));
+
+    // Fault pins are open drain outputs of the motor drivers
+    unwrap!(spawner.spawn(fault::watch_fault(0, Input::new(p.PIN_9, Pull::Up))));
+    unwrap!(spawner.spawn(fault::watch_fault(1, Input::new(p.PIN_17, Pull::Up))));
+    unwrap!(spawner.spawn(fault::watch_fault(2, Input::new(p.PIN_5, Pull::Up))));
+    unwrap!(spawner.spawn(fault::watch_fault(3, Input::new(p.PIN_13, Pull::Up))));
 
     unwrap!(spawner.spawn(safety_watchdog::start_safety_watch_dog()));
     unwrap!(spawner.spawn(serial::usb::start_usb(spawner, p.USB)));
-    unwrap!(spawner.spawn(serial::uart::start_uart(spawner, p.UART0, p.PIN_0, p.PIN_1)));
-    unwrap!(spawner.spawn(serial::i2c::start_i2c(spawner, p.I2C1, p.PIN_19, p.PIN_18)));
+    unwrap!(spawner.spawn(serial::uart::start_uart(
+        spawner,
+        p.UART0,
+        p.PIN_0,
+        p.PIN_1,
+        device_config.uart_baud
+    )));
+    unwrap!(spawner.spawn(serial::i2c::start_i2c(
+        spawner,
+        p.I2C1,
+        p.PIN_19,
+        p.PIN_18,
+        device_config.i2c_address
+    )));
     unwrap!(spawner.spawn(current::start_adc_dma(
-        spawner, p.ADC, p.DMA_CH0, p.PIN_26, p.PIN_27, p.PIN_28, p.PIN_29
+        spawner,
+        p.ADC,
+        p.DMA_CH0,
+        p.PIN_26,
+        p.PIN_27,
+        p.PIN_28,
+        p.PIN_29,
+        p.ADC_TEMP_SENSOR
     )));
+
+    // Started last so a slow boot does not trip it
+    unwrap!(spawner.spawn(hardware_watchdog::run_hardware_watchdog()));
 }
@@ -1,91 +1,359 @@
 use embassy_rp::{
-    gpio::{AnyPin, Input, Level, Output, Pull},
+    gpio::{AnyPin, Level, Output},
     pwm::{ChannelAPin, Config, Pwm, SetDutyCycle, Slice},
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant};
+use interface::{Failsafe, MotorConfig, c2h::MotorFault};
 
-use crate::current;
+use crate::{current, fault};
 
-pub static MOTOR_CONTROLLERS: Mutex<CriticalSectionRawMutex, Option<[Drv8874; 4]>> =
+/// Setpoints of every motor, applied to the motor drivers by the control loop
+pub static MOTOR_CONTROLLERS: Mutex<CriticalSectionRawMutex, Option<[MotorController; 4]>> =
     Mutex::new(None);
 
-pub struct Drv8874 {
+/// How fast the foldback reacts, in full scale per second per unit of relative current error
+const FOLDBACK_GAIN: f32 = 20.0;
+/// How long a failsafe brakes before the driver sleeps, long enough for the motor to stop
+const FAILSAFE_BRAKE_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+pub struct CurrentLimit {
+    pub amps: f32,
+    pub trip_time: Duration,
+}
+
+/// Failsafe a disarmed motor is in, disarmed motors coast otherwise
+#[derive(Clone, Copy)]
+enum ActiveFailsafe {
+    Brake { until: Instant },
+    Hold { until: Instant },
+}
+
+pub struct MotorController {
     motor_id: u8,
-    pwm: Pwm<'static>,
-    phase: Output<'static>,
-    enable: Output<'static>,
-    fault: Input<'static>,
 
     last_speed: f32,
+    /// Commanded speed after the acceleration limits
+    ramped_speed: f32,
+    applied_speed: f32,
     armed: bool,
+
+    failsafe: Failsafe,
+    active_failsafe: Option<ActiveFailsafe>,
+
+    /// Full scale per second, infinite when unlimited
+    acceleration: f32,
+    deceleration: f32,
+
+    current_limit: Option<CurrentLimit>,
+    over_limit_since: Option<Instant>,
+    overcurrent: bool,
+
+    current_ceiling: Option<f32>,
+    /// Fraction of the commanded duty let through by the current foldback
+    foldback: f32,
+    last_tick: Option<Instant>,
 }
 
-impl Drv8874 {
-    pub fn new<T: Slice>(
-        motor_id: u8,
-        slice: T,
-        pwm: impl ChannelAPin<T>,
-        phase: impl Into<AnyPin>,
-        enable: impl Into<AnyPin>,
-        fault: impl Into<AnyPin>,
-    ) -> Self {
+impl MotorController {
+    pub const fn new(motor_id: u8) -> Self {
         Self {
             motor_id,
-            pwm: Pwm::new_output_a(slice, pwm, Config::default()),
-            phase: Output::new(phase.into(), Level::Low),
-            enable: Output::new(enable.into(), Level::Low),
-            fault: Input::new(fault.into(), Pull::Up),
-            armed: false,
             last_speed: 0.0,
+            ramped_speed: 0.0,
+            applied_speed: 0.0,
+            armed: false,
+            failsafe: Failsafe::Coast,
+            active_failsafe: None,
+            acceleration: f32::INFINITY,
+            deceleration: f32::INFINITY,
+            current_limit: None,
+            over_limit_since: None,
+            overcurrent: false,
+            current_ceiling: None,
+            foldback: 1.0,
+            last_tick: None,
         }
     }
 
-    pub fn set_speed(&mut self, speed: f32) {
+    /// Rejected speeds stop the motor and end an active failsafe
+    pub fn set_speed(&mut self, speed: f32) -> Result<(), MotorFault> {
+        if self.overcurrent {
+            self.stop();
+            self.active_failsafe = None;
+            return Err(MotorFault::Overcurrent);
+        }
+
         if !self.armed {
-            let _ = self.pwm.set_duty_cycle_fully_off();
-            self.last_speed = 0.0;
-            return;
+            self.stop();
+            self.active_failsafe = None;
+            return Err(MotorFault::Disarmed);
         }
-        self.set_armed(self.armed);
 
-        let duty = speed.abs() * self.pwm.max_duty_cycle() as f32;
+        self.last_speed = speed;
 
-        self.phase.set_level((speed >= 0.0).into());
-        let _ = self.pwm.set_duty_cycle(duty as u16);
+        Ok(())
+    }
 
-        self.last_speed = speed;
+    fn stop(&mut self) {
+        self.last_speed = 0.0;
+        self.ramped_speed = 0.0;
+        self.applied_speed = 0.0;
     }
 
+    /// Arming or disarming ends an active failsafe, disarmed motors coast
     pub fn set_armed(&mut self, armed: bool) {
-        if armed != self.armed {
-            let _ = self.pwm.set_duty_cycle_fully_off();
-            self.last_speed = 0.0;
+        if armed != self.armed || self.active_failsafe.is_some() {
+            self.stop();
         }
 
-        self.enable.set_level(armed.into());
         self.armed = armed;
+        self.active_failsafe = None;
+    }
+
+    /// Disarms an armed motor into its configured failsafe, returns the failsafe it is in
+    ///
+    /// Motors that were not armed keep coasting, so do motors set to hold when not `may_drive`
+    pub fn enter_failsafe(&mut self, now: Instant, may_drive: bool) -> Failsafe {
+        if !self.armed {
+            return Failsafe::Coast;
+        }
+
+        let failsafe = match &self.failsafe {
+            Failsafe::Hold { .. } if !may_drive => Failsafe::Coast,
+            failsafe => failsafe.clone(),
+        };
+
+        self.armed = false;
+        self.active_failsafe = match &failsafe {
+            Failsafe::Coast => {
+                self.stop();
+                None
+            }
+            Failsafe::Brake => {
+                self.stop();
+                Some(ActiveFailsafe::Brake {
+                    until: now + FAILSAFE_BRAKE_TIME,
+                })
+            }
+            Failsafe::Hold { speed, duration } => {
+                // Ramps from the current speed like any other command
+                self.last_speed = speed.as_f32();
+                Some(ActiveFailsafe::Hold {
+                    until: now + duration.as_duration(),
+                })
+            }
+        };
+
+        failsafe
+    }
+
+    /// Applies the stored settings of this motor
+    pub fn configure(&mut self, config: &MotorConfig) {
+        self.set_current_limit((config.current_limit.0 > 0).then(|| CurrentLimit {
+            amps: config.current_limit.as_f32_amps(),
+            trip_time: config.trip_time.as_duration(),
+        }));
+        self.set_current_ceiling(
+            (config.current_ceiling.0 > 0).then(|| config.current_ceiling.as_f32_amps()),
+        );
+        self.set_ramp_rates(config.acceleration.as_f32(), config.deceleration.as_f32());
+        self.failsafe = config.failsafe.clone();
+    }
+
+    /// Limits how fast the applied speed moves away from and towards zero, in full scale per
+    /// second. `None` removes the limit
+    pub fn set_ramp_rates(&mut self, acceleration: Option<f32>, deceleration: Option<f32>) {
+        self.acceleration = acceleration.unwrap_or(f32::INFINITY);
+        self.deceleration = deceleration.unwrap_or(f32::INFINITY);
+    }
+
+    pub fn set_current_limit(&mut self, current_limit: Option<CurrentLimit>) {
+        self.current_limit = current_limit;
+        self.over_limit_since = None;
+    }
+
+    /// Holds the current at `ceiling` amps by lowering the duty cycle, `None` disables the foldback
+    pub fn set_current_ceiling(&mut self, ceiling: Option<f32>) {
+        self.current_ceiling = ceiling;
+
+        if ceiling.is_none() {
+            self.foldback = 1.0;
+        }
+    }
+
+    /// Advances the ramp, foldback and overcurrent trip using a new current reading, returns true
+    /// when the trip just latched
+    pub fn tick(&mut self, amps: f32, now: Instant) -> bool {
+        let dt = self
+            .last_tick
+            .map(|last| (now - last).as_micros() as f32 / 1e6)
+            .unwrap_or(0.0);
+        self.last_tick = Some(now);
+
+        match self.active_failsafe {
+            Some(ActiveFailsafe::Hold { until }) if now >= until => {
+                self.stop();
+                self.active_failsafe = Some(ActiveFailsafe::Brake {
+                    until: now + FAILSAFE_BRAKE_TIME,
+                });
+            }
+            Some(ActiveFailsafe::Brake { until }) if now >= until => self.active_failsafe = None,
+            _ => {}
+        }
+
+        self.update_ramp(dt);
+        self.update_foldback(amps, dt);
+        let tripped = self.update_overcurrent_trip(amps, now);
+
+        let holding = matches!(self.active_failsafe, Some(ActiveFailsafe::Hold { .. }));
+        self.applied_speed = if (self.armed || holding) && !self.overcurrent {
+            self.ramped_speed * self.foldback
+        } else {
+            0.0
+        };
+
+        tripped
+    }
+
+    fn update_ramp(&mut self, dt: f32) {
+        let current = self.ramped_speed;
+
+        // Reversals slow down to a stop before speeding up in the other direction
+        let target = if current * self.last_speed < 0.0 {
+            0.0
+        } else {
+            self.last_speed
+        };
+
+        let rate = if target.abs() > current.abs() {
+            self.acceleration
+        } else {
+            self.deceleration
+        };
+
+        self.ramped_speed = if rate.is_infinite() {
+            target
+        } else {
+            let max_step = rate * dt;
+            current + (target - current).clamp(-max_step, max_step)
+        };
+    }
+
+    fn update_foldback(&mut self, amps: f32, dt: f32) {
+        let Some(ceiling) = self.current_ceiling else {
+            return;
+        };
+
+        if amps < 0.0 {
+            // No reading
+            return;
+        }
+
+        let error = (ceiling - amps) / ceiling;
+        self.foldback = (self.foldback + FOLDBACK_GAIN * error * dt).clamp(0.0, 1.0);
+    }
+
+    fn update_overcurrent_trip(&mut self, amps: f32, now: Instant) -> bool {
+        let Some(current_limit) = self.current_limit else {
+            return false;
+        };
+
+        if self.overcurrent || amps <= current_limit.amps {
+            self.over_limit_since = None;
+            return false;
+        }
+
+        let over_limit_since = *self.over_limit_since.get_or_insert(now);
+        if now - over_limit_since < current_limit.trip_time {
+            return false;
+        }
+
+        self.stop();
+        self.overcurrent = true;
+        self.over_limit_since = None;
+
+        true
+    }
+
+    /// Clears latched faults, the motor stays stopped until the next speed command
+    pub fn clear_faults(&mut self) {
+        self.overcurrent = false;
+    }
+
+    pub fn is_overcurrent(&self) -> bool {
+        self.overcurrent
     }
 
     pub fn is_armed(&self) -> bool {
         self.armed
     }
 
+    /// The driver stays enabled, either armed or braking or holding as a failsafe
+    pub fn is_driven(&self) -> bool {
+        self.armed || self.active_failsafe.is_some()
+    }
+
     pub fn is_fault(&self) -> bool {
-        // Fault pin is active low
-        self.fault.is_low()
+        fault::is_fault(self.motor_id)
     }
 
     pub fn motor_id(&self) -> u8 {
         self.motor_id
     }
 
+    /// Speed last commanded by a host
     pub fn last_speed(&self) -> f32 {
         self.last_speed
     }
 
+    /// Speed currently applied to the driver after ramping and current limiting
+    pub fn applied_speed(&self) -> f32 {
+        self.applied_speed
+    }
+
+    /// Low pass filtered current, see [`current::average_current`]
     pub fn current_draw(&self) -> f32 {
-        current::ADC_WATCHES[self.motor_id as usize]
-            .try_get()
-            .unwrap_or(-1.0)
+        current::average_current(self.motor_id)
+    }
+}
+
+/// DRV8874 motor driver in PH/EN mode, owned by the control loop
+pub struct Drv8874 {
+    pwm: Pwm<'static>,
+    phase: Output<'static>,
+    enable: Output<'static>,
+}
+
+impl Drv8874 {
+    pub fn new<T: Slice>(
+        slice: T,
+        pwm: impl ChannelAPin<T>,
+        phase: impl Into<AnyPin>,
+        enable: impl Into<AnyPin>,
+    ) -> Self {
+        Self {
+            pwm: Pwm::new_output_a(slice, pwm, Config::default()),
+            phase: Output::new(phase.into(), Level::Low),
+            enable: Output::new(enable.into(), Level::Low),
+        }
+    }
+
+    /// Drives the motor at `speed` in `-1.0..=1.0`, the driver sleeps while not `enabled`
+    ///
+    /// A speed of zero brakes while `enabled` and coasts otherwise
+    pub fn apply(&mut self, enabled: bool, speed: f32) {
+        self.enable.set_level(enabled.into());
+
+        if speed == 0.0 {
+            let _ = self.pwm.set_duty_cycle_fully_off();
+            return;
+        }
+
+        let duty = speed.abs() * self.pwm.max_duty_cycle() as f32;
+
+        self.phase.set_level((speed >= 0.0).into());
+        let _ = self.pwm.set_duty_cycle(duty as u16);
     }
 }
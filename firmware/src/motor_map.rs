@@ -0,0 +1,40 @@
+//! Runtime mapping of logical motors onto the physical outputs of the board
+//!
+//! Everything facing the hosts uses logical motor ids, while the motor drivers, fault pins and
+//! current sense channels are indexed by physical output
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use interface::MotorMap;
+
+static MOTOR_MAP: Mutex<CriticalSectionRawMutex, Cell<MotorMap>> =
+    Mutex::new(Cell::new(MotorMap::IDENTITY));
+
+pub fn get() -> MotorMap {
+    MOTOR_MAP.lock(|map| map.get())
+}
+
+/// Replaces the motor map, returns false and keeps the old map if `map` is invalid
+pub fn set(map: MotorMap) -> bool {
+    if !map.is_valid() {
+        return false;
+    }
+
+    MOTOR_MAP.lock(|cell| cell.set(map));
+    true
+}
+
+/// Physical output driven by `motor_id`
+pub fn output(motor_id: u8) -> usize {
+    get().outputs[motor_id as usize] as usize
+}
+
+/// Logical motor driving `output`
+pub fn motor_id(output: usize) -> u8 {
+    get()
+        .outputs
+        .iter()
+        .position(|&it| it as usize == output)
+        .unwrap_or(output) as u8
+}
@@ -0,0 +1,89 @@
+//! Arbitrates which interface may send motion and arming commands
+//!
+//! While no interface holds control every interface may command the motors, once one claims it the
+//! others are rejected until it releases control, loses its link or an interface with a higher
+//! [`DeviceConfig::port_priority`](interface::DeviceConfig::port_priority) takes over
+
+use core::cell::Cell;
+
+use defmt::{Debug2Format, debug, info};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use interface::{Port, c2h};
+
+use crate::{config, serial::handler::SERIAL_CTXS};
+
+static OWNER: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<Port>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+pub fn owner() -> Option<Port> {
+    OWNER.lock(|cell| cell.get())
+}
+
+/// `port` holds control or nobody does
+pub fn may_command(port: Port) -> bool {
+    owner().is_none_or(|owner| owner == port)
+}
+
+pub fn status(port: Port) -> c2h::ControlStatus {
+    let owner = owner();
+
+    c2h::ControlStatus {
+        owner,
+        is_owner: owner == Some(port),
+    }
+}
+
+/// Gives control to `port` unless an interface of equal or higher priority holds it
+pub fn claim(port: Port) -> bool {
+    let port_priority = config::get().port_priority;
+    // Only serial ports have a priority
+    let priority = |port: Port| port_priority.get(port as usize).copied().unwrap_or(0);
+
+    let previous = OWNER.lock(|cell| {
+        let owner = cell.get();
+        let granted = owner.is_none_or(|owner| owner == port || priority(port) > priority(owner));
+
+        if granted {
+            cell.set(Some(port));
+        }
+
+        granted.then_some(owner)
+    });
+
+    let Some(previous) = previous else {
+        return false;
+    };
+
+    if previous != Some(port) {
+        info!("{} took control", Debug2Format(&port));
+        notify_change(port);
+    }
+
+    true
+}
+
+/// Gives up control if `port` holds it
+pub fn release(port: Port) {
+    let released = OWNER.lock(|cell| {
+        let released = cell.get() == Some(port);
+        if released {
+            cell.set(None);
+        }
+
+        released
+    });
+
+    if released {
+        info!("{} released control", Debug2Format(&port));
+        notify_change(port);
+    }
+}
+
+/// Tells every serial interface apart from `origin`, which gets its own reply, about the new owner
+fn notify_change(origin: Port) {
+    for ctx in SERIAL_CTXS.iter().filter(|ctx| ctx.port != origin) {
+        if ctx.packets.try_send(status(ctx.port).into()).is_err() {
+            debug!("Dropped control status, packet queue is full");
+        }
+    }
+}
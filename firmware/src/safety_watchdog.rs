@@ -1,40 +1,78 @@
 use defmt::warn;
+use embassy_futures::select::{Either, select};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{Duration, Instant, Timer};
+use interface::{Failsafe, c2h};
 
-use crate::motor_controller::MOTOR_CONTROLLERS;
+use crate::{
+    emergency_stop,
+    hardware_watchdog::{self, Task},
+    motor_controller::{MOTOR_CONTROLLERS, MotorController},
+    serial::handler::broadcast_packet,
+    temperature,
+};
 
 static WATCH_DOG_DEADLINE: Signal<CriticalSectionRawMutex, Instant> = Signal::new();
 
 #[embassy_executor::task]
 pub async fn start_safety_watch_dog() {
-    async fn set_all(enabled: bool) {
+    async fn arm_all() {
         let mut motor_controllers = MOTOR_CONTROLLERS.lock().await;
 
+        // Checked under the lock, emergency stops and thermal trips disarm under it as well
+        if emergency_stop::is_latched() || temperature::is_overheated() {
+            return;
+        }
+
         if let Some(motor_controllers) = &mut *motor_controllers {
             for motor in motor_controllers {
-                motor.set_armed(enabled);
+                motor.set_armed(true);
             }
         }
     }
 
-    loop {
-        let deadline = WATCH_DOG_DEADLINE.wait().await;
+    /// Disarms every armed motor into its configured failsafe
+    async fn enter_failsafe() -> [Failsafe; 4] {
+        let mut failsafe = [const { Failsafe::Coast }; 4];
+        let mut motor_controllers = MOTOR_CONTROLLERS.lock().await;
 
-        if deadline == Instant::MAX {
-            set_all(false).await;
-            continue;
-        }
+        // Holding keeps the motors running, which an emergency stop or thermal trip must not allow
+        let may_drive = !emergency_stop::is_latched() && !temperature::is_overheated();
 
-        if deadline > Instant::now() {
-            set_all(true).await;
+        if let Some(motor_controllers) = &mut *motor_controllers {
+            let now = Instant::now();
+            for (motor, failsafe) in motor_controllers.iter_mut().zip(&mut failsafe) {
+                *failsafe = motor.enter_failsafe(now, may_drive);
+            }
         }
 
-        Timer::at(deadline).await;
+        failsafe
+    }
+
+    let mut deadline = Instant::MAX;
+
+    loop {
+        // A new deadline or disarm takes effect right away instead of once the old deadline elapses
+        let event = select(WATCH_DOG_DEADLINE.wait(), Timer::at(deadline)).await;
+        let _busy = hardware_watchdog::busy(Task::SafetyWatchdog);
 
-        if !WATCH_DOG_DEADLINE.signaled() {
-            warn!("Saftey watch dog deadline elapsed");
-            set_all(false).await;
+        match event {
+            Either::First(new_deadline) => {
+                deadline = new_deadline;
+
+                if deadline == Instant::MAX {
+                    disarm_controllers(&mut MOTOR_CONTROLLERS.lock().await);
+                } else if deadline > Instant::now() {
+                    arm_all().await;
+                }
+            }
+            Either::Second(()) => {
+                warn!("Saftey watch dog deadline elapsed");
+                deadline = Instant::MAX;
+
+                let failsafe = enter_failsafe().await;
+                broadcast_packet(c2h::DeadlineExpired { failsafe }.into());
+            }
         }
     }
 }
@@ -43,6 +81,22 @@ pub fn feed_safety_watch_dog(dur: Duration) {
     WATCH_DOG_DEADLINE.signal(Instant::now() + dur);
 }
 
+/// Disarms every motor once the safety watch dog task runs, see [`disarm`] to disarm right away
 pub fn disable_motors() {
     WATCH_DOG_DEADLINE.signal(Instant::MAX);
 }
+
+/// Disarms every motor before returning and drops the deadline, for callers holding
+/// [`MOTOR_CONTROLLERS`]
+pub fn disarm(motor_controllers: &mut Option<[MotorController; 4]>) {
+    disarm_controllers(motor_controllers);
+    disable_motors();
+}
+
+fn disarm_controllers(motor_controllers: &mut Option<[MotorController; 4]>) {
+    if let Some(motor_controllers) = motor_controllers {
+        for motor in motor_controllers {
+            motor.set_armed(false);
+        }
+    }
+}
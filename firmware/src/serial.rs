@@ -1,4 +1,5 @@
 pub mod handler;
 pub mod i2c;
+pub mod link;
 pub mod uart;
 pub mod usb;
@@ -1,36 +1,89 @@
+use defmt::{debug, warn};
 use embassy_futures::select::{Either, select};
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, TrySendError},
+    signal::Signal,
 };
 use embassy_time::{Duration, Instant, Timer};
 
-use crate::{motor_controller, safety_watchdog};
+use super::{uart, usb};
+use crate::{
+    build_info, config, control_loop,
+    current::{self, PeakCurrent},
+    emergency_stop,
+    hardware_watchdog::{self, Task},
+    motor_controller::{self, MotorController},
+    motor_map, ownership, safety_watchdog, temperature,
+};
 
 use interface::{
-    CurrentDraw, Motors, Speed,
+    ConfigParam, ConfigRejection, CurrentDraw, CurrentKind, MotorConfig, Motors, ParamValue, Port,
+    Speed,
     c2h::{self, PacketC2H},
     decoder::{FeedResult, PackerDecoder},
     h2c::{self, PacketH2C},
 };
 
 pub struct HandlerCtx {
+    pub port: Port,
     pub packets: Channel<CriticalSectionRawMutex, PacketC2H, 8>,
-    pub streams: Signal<CriticalSectionRawMutex, (Motors, Duration)>,
+    pub streams: Signal<CriticalSectionRawMutex, StreamConfig>,
+    pub peak_current: PeakCurrent,
 }
 
 impl HandlerCtx {
-    pub const fn new() -> Self {
+    pub const fn new(port: Port) -> Self {
         Self {
+            port,
             packets: Channel::new(),
             streams: Signal::new(),
+            peak_current: PeakCurrent::new(),
         }
     }
-}
 
-impl Default for HandlerCtx {
-    fn default() -> Self {
-        Self::new()
+    /// Queues `packet` for the host
+    ///
+    /// A host that stops reading fills the queue, waiting for room is backpressure rather than a
+    /// hang so it does not count against the hardware watchdog
+    pub async fn send(&self, packet: PacketC2H) {
+        let Err(TrySendError::Full(packet)) = self.packets.try_send(packet) else {
+            return;
+        };
+
+        let _idle = self.watchdog_task().map(hardware_watchdog::idle);
+        self.packets.send(packet).await;
     }
+
+    /// Task reading the packets of this interface, I2C is not watched
+    fn watchdog_task(&self) -> Option<Task> {
+        match self.port {
+            Port::Usb => Some(Task::UsbRead),
+            Port::Uart => Some(Task::UartRead),
+            Port::I2c => None,
+        }
+    }
+}
+
+/// Contexts of the serial interfaces, which receive broadcasts and can stream motor state
+pub static SERIAL_CTXS: [&HandlerCtx; 2] = [&usb::USB_CTX, &uart::UART_CTX];
+
+/// Motor state stream of an interface, see [`h2c::StartStream`]
+#[derive(Clone, Copy)]
+pub struct StreamConfig {
+    motors: Motors,
+    interval: Duration,
+    current: CurrentKind,
+    charge: bool,
+}
+
+impl StreamConfig {
+    const STOPPED: Self = Self {
+        motors: Motors::empty(),
+        interval: Duration::MAX,
+        current: CurrentKind::Instantaneous,
+        charge: false,
+    };
 }
 
 pub async fn feed_all_and_handle<const N: usize>(
@@ -45,13 +98,11 @@ pub async fn feed_all_and_handle<const N: usize>(
                 data = &[];
             }
             FeedResult::OverFull(remaining) => {
-                ctx.packets
-                    .send(c2h::Error::DecodingBufferOverflow.into())
-                    .await;
+                ctx.send(c2h::Error::DecodingBufferOverflow.into()).await;
                 data = remaining;
             }
             FeedResult::DeserError(remaining) => {
-                ctx.packets.send(c2h::Error::DecodingError.into()).await;
+                ctx.send(c2h::Error::DecodingError.into()).await;
                 data = remaining;
             }
             FeedResult::Success {
@@ -66,30 +117,264 @@ pub async fn feed_all_and_handle<const N: usize>(
 }
 
 pub async fn handle_inbound_packet(ctx: &HandlerCtx, packet: impl Into<PacketH2C>) {
-    match packet.into() {
+    let packet = packet.into();
+
+    if requires_control(&packet) && !ownership::may_command(ctx.port) {
+        debug!("Rejected command, another interface holds control");
+        ctx.send(c2h::Error::NotOwner.into()).await;
+        return;
+    }
+
+    match packet {
         PacketH2C::StartStream(start_stream) => {
-            ctx.streams
-                .signal((start_stream.motors, start_stream.interval.as_duration()));
+            ctx.streams.signal(StreamConfig {
+                motors: start_stream.motors,
+                interval: start_stream.interval.as_duration(),
+                current: start_stream.current,
+                charge: start_stream.charge,
+            });
         }
         PacketH2C::SetSpeed(set_speed) => {
-            let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
-            let Some(motor_controllers) = &mut *motor_controllers else {
-                return;
-            };
+            let mut errors = MotorErrors::new();
+            push_invalid_motors(set_speed.motors, &mut errors);
+
+            {
+                let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
+                let Some(motor_controllers) = &mut *motor_controllers else {
+                    return;
+                };
+
+                for (_, motor_id) in set_speed.motors.iter_names() {
+                    let motor_id = motor_id.bits().trailing_zeros();
+                    let motor = &mut motor_controllers[motor_id as usize];
+
+                    set_motor_speed(motor, &set_speed.speed, &mut errors);
+                }
+            }
+
+            report_errors(ctx, &errors);
+        }
+        PacketH2C::SetSpeeds(set_speeds) => {
+            let mut errors = MotorErrors::new();
+
+            {
+                // All speeds are applied under the same lock so the motors change together
+                let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
+                let Some(motor_controllers) = &mut *motor_controllers else {
+                    return;
+                };
+
+                for (motor, speed) in motor_controllers.iter_mut().zip(&set_speeds.speeds) {
+                    if let Some(speed) = speed {
+                        set_motor_speed(motor, speed, &mut errors);
+                    }
+                }
+            }
+
+            report_errors(ctx, &errors);
+        }
+        PacketH2C::SetCurrentLimit(set_current_limit) => {
+            let mut errors = MotorErrors::new();
+            push_invalid_motors(set_current_limit.motors, &mut errors);
+
+            configure_motors(set_current_limit.motors, |motor| {
+                motor.current_limit = set_current_limit.limit.clone();
+                motor.trip_time = set_current_limit.trip_time.clone();
+            })
+            .await;
+
+            report_errors(ctx, &errors);
+        }
+        PacketH2C::SetCurrentCeiling(set_current_ceiling) => {
+            let mut errors = MotorErrors::new();
+            push_invalid_motors(set_current_ceiling.motors, &mut errors);
+
+            configure_motors(set_current_ceiling.motors, |motor| {
+                motor.current_ceiling = set_current_ceiling.ceiling.clone();
+            })
+            .await;
+
+            report_errors(ctx, &errors);
+        }
+        PacketH2C::SetRampRate(set_ramp_rate) => {
+            let mut errors = MotorErrors::new();
+            push_invalid_motors(set_ramp_rate.motors, &mut errors);
+
+            configure_motors(set_ramp_rate.motors, |motor| {
+                motor.acceleration = set_ramp_rate.acceleration.clone();
+                motor.deceleration = set_ramp_rate.deceleration.clone();
+            })
+            .await;
+
+            report_errors(ctx, &errors);
+        }
+        PacketH2C::SetControlLoopRate(set_control_loop_rate) => {
+            let rate_hz = control_loop::set_rate(set_control_loop_rate.rate_hz);
+            config::update(|config| config.control_loop_rate = rate_hz);
+        }
+        PacketH2C::ReadControlLoopStats => {
+            ctx.send(control_loop::stats().into()).await;
+        }
+        PacketH2C::ResetControlLoopStats => {
+            control_loop::reset_stats();
+        }
+        PacketH2C::SetMotorMap(set_motor_map) => {
+            if set_motor_map.map.is_valid() {
+                let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
+
+                // Motors must not keep running on their new outputs
+                safety_watchdog::disarm(&mut motor_controllers);
+                motor_map::set(set_motor_map.map);
+                config::update(|config| config.motor_map = set_motor_map.map);
+            } else {
+                ctx.send(c2h::Error::InvalidConfig.into()).await;
+            }
+        }
+        PacketH2C::ReadMotorMap => {
+            ctx.send(
+                c2h::MotorMapResponse {
+                    map: motor_map::get(),
+                }
+                .into(),
+            )
+            .await;
+        }
+        PacketH2C::ReadConfig => {
+            ctx.send(
+                c2h::ConfigResponse {
+                    config: config::get(),
+                }
+                .into(),
+            )
+            .await;
+        }
+        PacketH2C::WriteConfig(write_config) => {
+            if write_config.config.is_valid() {
+                config::apply(write_config.config).await;
+            } else {
+                ctx.send(c2h::Error::InvalidConfig.into()).await;
+            }
+        }
+        PacketH2C::CommitConfig => {
+            if let Err(err) = config::commit().await {
+                ctx.send(err.into()).await;
+            }
+        }
+        PacketH2C::FactoryReset => {
+            if let Err(err) = config::factory_reset().await {
+                ctx.send(err.into()).await;
+            }
+        }
+        PacketH2C::GetConfig(get_config) => {
+            let result = config::get().get(get_config.param);
+            ctx.send(config_response(get_config.param, result)).await;
+        }
+        PacketH2C::SetConfig(set_config) => {
+            let param = set_config.param;
+            let value = set_config.value.clone();
+
+            let result = config::modify(|config| config.set(param, set_config.value))
+                .await
+                .map(|()| value);
+            ctx.send(config_response(param, result)).await;
+        }
+        PacketH2C::CalibrateCurrent => match current::calibrate().await {
+            Ok(offsets) => ctx.send(c2h::CurrentCalibrated { offsets }.into()).await,
+            Err(err) => ctx.send(err.into()).await,
+        },
+        PacketH2C::ReadCharge => {
+            let charge = core::array::from_fn(|motor_id| current::charge(motor_id as u8));
+            ctx.send(c2h::ChargeResponse { charge }.into()).await;
+        }
+        PacketH2C::ResetCharge(reset_charge) => {
+            let mut errors = MotorErrors::new();
+            push_invalid_motors(reset_charge.motors, &mut errors);
+
+            for (_, motor_id) in reset_charge.motors.iter_names() {
+                current::reset_charge(motor_id.bits().trailing_zeros() as u8);
+            }
+
+            report_errors(ctx, &errors);
+        }
+        PacketH2C::ReadDeviceStatus => {
+            ctx.send(
+                c2h::DeviceStatus {
+                    temperature: temperature::temperature(),
+                    overheated: temperature::is_overheated(),
+                    reset_reason: hardware_watchdog::reset_reason(),
+                    emergency_stop: emergency_stop::token(),
+                }
+                .into(),
+            )
+            .await;
+        }
+        PacketH2C::ClaimControl => {
+            ownership::claim(ctx.port);
+            ctx.send(ownership::status(ctx.port).into()).await;
+        }
+        PacketH2C::ReleaseControl => {
+            ownership::release(ctx.port);
+            ctx.send(ownership::status(ctx.port).into()).await;
+        }
+        PacketH2C::ReadControlStatus => {
+            ctx.send(ownership::status(ctx.port).into()).await;
+        }
+        PacketH2C::EmergencyStop => emergency_stop::trigger(ctx.port).await,
+        PacketH2C::ClearEmergencyStop(clear_emergency_stop) => {
+            let token = clear_emergency_stop.token;
 
-            for (_, motor_id) in set_speed.motors.iter_names() {
-                let motor_id = motor_id.bits().trailing_zeros();
-                let motor = &mut motor_controllers[motor_id as usize];
+            match emergency_stop::clear(token, ctx.port) {
+                // Released emergency stops are broadcast
+                Ok(true) => {}
+                Ok(false) => {
+                    ctx.send(
+                        c2h::EmergencyStopEvent {
+                            latched: false,
+                            token,
+                            source: ctx.port,
+                        }
+                        .into(),
+                    )
+                    .await
+                }
+                Err(err) => ctx.send(err.into()).await,
+            }
+        }
+        PacketH2C::ClearFaults(clear_faults) => {
+            let mut errors = MotorErrors::new();
+            push_invalid_motors(clear_faults.motors, &mut errors);
 
-                motor.set_speed(set_speed.speed.as_f32());
+            {
+                let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
+                let Some(motor_controllers) = &mut *motor_controllers else {
+                    return;
+                };
+
+                for (_, motor_id) in clear_faults.motors.iter_names() {
+                    let motor_id = motor_id.bits().trailing_zeros();
+                    motor_controllers[motor_id as usize].clear_faults();
+                }
             }
+
+            report_errors(ctx, &errors);
         }
         PacketH2C::Ping(ping) => {
             let pong = c2h::Pong { id: ping.id };
-            ctx.packets.send(pong.into()).await;
+            ctx.send(pong.into()).await;
         }
         PacketH2C::SetArmed(set_armed) => match set_armed {
             h2c::SetArmed::Armed { duration } => {
+                // Refused arms must not extend the deadline of motors that are still armed
+                if emergency_stop::is_latched() {
+                    ctx.send(c2h::Error::EmergencyStop.into()).await;
+                    return;
+                }
+
+                if temperature::is_overheated() {
+                    ctx.send(c2h::Error::Overheated.into()).await;
+                    return;
+                }
+
                 safety_watchdog::feed_safety_watch_dog(duration.as_duration())
             }
             h2c::SetArmed::Disarmed => safety_watchdog::disable_motors(),
@@ -98,59 +383,173 @@ pub async fn handle_inbound_packet(ctx: &HandlerCtx, packet: impl Into<PacketH2C
             embassy_rp::rom_data::reset_to_usb_boot(0, 0);
         }
         PacketH2C::ReadProtocolVersion => {
-            ctx.packets
-                .send(
-                    c2h::ProtocolVersionResponse {
-                        version: interface::PROTOCOL_VERSION,
-                    }
-                    .into(),
-                )
-                .await;
+            ctx.send(
+                c2h::ProtocolVersionResponse {
+                    version: interface::PROTOCOL_VERSION,
+                }
+                .into(),
+            )
+            .await;
         }
         PacketH2C::ReadSoftwareData => {
-            ctx.packets.send(c2h::Error::Unimplemented.into()).await;
+            ctx.send(build_info::software_data().into()).await;
+        }
+    }
+}
+
+/// Commands that move the motors, change how they are driven or interrupt them, which only the
+/// interface holding control may send
+///
+/// Disarming, emergency stops and reads are always allowed
+fn requires_control(packet: &PacketH2C) -> bool {
+    matches!(
+        packet,
+        PacketH2C::SetSpeed(_)
+            | PacketH2C::SetSpeeds(_)
+            | PacketH2C::ClearFaults(_)
+            | PacketH2C::SetArmed(h2c::SetArmed::Armed { .. })
+            | PacketH2C::ClearEmergencyStop(_)
+            | PacketH2C::SetCurrentLimit(_)
+            | PacketH2C::SetCurrentCeiling(_)
+            | PacketH2C::SetRampRate(_)
+            | PacketH2C::SetControlLoopRate(_)
+            | PacketH2C::SetMotorMap(_)
+            | PacketH2C::WriteConfig(_)
+            | PacketH2C::SetConfig(_)
+            | PacketH2C::CommitConfig
+            | PacketH2C::FactoryReset
+            | PacketH2C::CalibrateCurrent
+            | PacketH2C::ResetCharge(_)
+            | PacketH2C::ResetToUsbBoot
+    )
+}
+
+/// Sends `packet` to every serial interface, dropping it for interfaces whose queue is full
+pub fn broadcast_packet(packet: PacketC2H) {
+    for ctx in SERIAL_CTXS {
+        if ctx.packets.try_send(packet.clone()).is_err() {
+            debug!("Dropped broadcast packet, packet queue is full");
+        }
+    }
+}
+
+fn config_response(param: ConfigParam, result: Result<ParamValue, ConfigRejection>) -> PacketC2H {
+    match result {
+        Ok(value) => c2h::ConfigValue { param, value }.into(),
+        Err(reason) => c2h::ConfigRejected { param, reason }.into(),
+    }
+}
+
+/// Errors caused by a single packet, reported once the motor controllers are unlocked
+type MotorErrors = heapless::Vec<c2h::MotorError, 8>;
+
+fn set_motor_speed(motor: &mut MotorController, speed: &Speed, errors: &mut MotorErrors) {
+    if motor.is_fault() {
+        let _ = errors.push(c2h::MotorError {
+            motor_id: motor.motor_id(),
+            fault: c2h::MotorFault::DriverFault,
+        });
+    }
+
+    if let Err(fault) = motor.set_speed(speed.as_f32()) {
+        let _ = errors.push(c2h::MotorError {
+            motor_id: motor.motor_id(),
+            fault,
+        });
+    }
+}
+
+/// Updates the stored settings of `motors` and applies them to their motor controllers
+async fn configure_motors(motors: Motors, update: impl Fn(&mut MotorConfig)) {
+    let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
+    let Some(motor_controllers) = &mut *motor_controllers else {
+        return;
+    };
+
+    config::update(|config| {
+        for (_, motor_id) in motors.iter_names() {
+            let motor_id = motor_id.bits().trailing_zeros() as usize;
+
+            update(&mut config.motors[motor_id]);
+            motor_controllers[motor_id].configure(&config.motors[motor_id]);
+        }
+    });
+}
+
+fn push_invalid_motors(motors: Motors, errors: &mut MotorErrors) {
+    let mut invalid = motors.bits() & !Motors::all().bits();
+
+    while invalid != 0 {
+        let motor_id = invalid.trailing_zeros() as u8;
+        invalid &= invalid - 1;
+
+        let _ = errors.push(c2h::MotorError {
+            motor_id,
+            fault: c2h::MotorFault::InvalidMotor,
+        });
+    }
+}
+
+fn report_errors(ctx: &HandlerCtx, errors: &[c2h::MotorError]) {
+    for error in errors {
+        // Error reports are best effort, never stall the packet handler on them
+        if ctx.packets.try_send(error.clone().into()).is_err() {
+            warn!("Dropped motor error report, packet queue is full");
         }
     }
 }
 
 #[embassy_executor::task(pool_size = 2)]
 pub async fn stream_motor_data(ctx: &'static HandlerCtx) {
-    let mut config = (Motors::empty(), Duration::MAX);
+    let mut config = StreamConfig::STOPPED;
 
     loop {
         let new_config_fut = ctx.streams.wait();
         // let interval_fut = Timer::after(config.1);
-        let interval_fut = Timer::at(Instant::now().checked_add(config.1).unwrap_or(Instant::MAX));
+        let interval_fut = Timer::at(
+            Instant::now()
+                .checked_add(config.interval)
+                .unwrap_or(Instant::MAX),
+        );
 
         let select = select(new_config_fut, interval_fut).await;
         match select {
             Either::First(new_config) => config = new_config,
-            Either::Second(()) => send_motor_stream(ctx, config.0).await,
+            Either::Second(()) => send_motor_stream(ctx, &config).await,
         }
     }
 }
 
-async fn send_motor_stream(ctx: &HandlerCtx, motors: Motors) {
-    let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
-    let Some(motor_controllers) = &mut *motor_controllers else {
-        return;
-    };
+async fn send_motor_stream(ctx: &HandlerCtx, config: &StreamConfig) {
+    let mut states = [const { None }; 4];
+
+    {
+        let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
+        let Some(motor_controllers) = &mut *motor_controllers else {
+            return;
+        };
 
-    for (_, motor_id) in motors.iter_names() {
-        let motor_id = motor_id.bits().trailing_zeros() as u8;
-        let motor = &mut motor_controllers[motor_id as usize];
+        for (_, motor_id) in config.motors.iter_names() {
+            let motor_id = motor_id.bits().trailing_zeros() as u8;
+            let motor = &mut motor_controllers[motor_id as usize];
 
-        ctx.packets
-            .send(
-                c2h::MotorState {
+            states[motor_id as usize] = Some(c2h::MotorState {
+                motor_id,
+                last_speed: Speed::from_f32(motor.last_speed()),
+                applied_speed: Speed::from_f32(motor.applied_speed()),
+                current_draw: CurrentDraw::from_f32_amps(current::read(
                     motor_id,
-                    last_speed: Speed::from_f32(motor.last_speed()),
-                    current_draw: CurrentDraw::from_f32_amps(motor.current_draw()),
-                    is_fault: motor.is_fault(),
-                    is_enabled: motor.is_armed(),
-                }
-                .into(),
-            )
-            .await;
+                    config.current,
+                    &ctx.peak_current,
+                )),
+                is_fault: motor.is_fault(),
+                is_enabled: motor.is_armed(),
+                is_overcurrent: motor.is_overcurrent(),
+                charge: config.charge.then(|| current::charge(motor_id)),
+            });
+        }
     }
+
+    // Send outside of the lock so a full channel does not stall the other interfaces
+    ctx.send(c2h::MotorStates { states }.into()).await;
 }
@@ -10,12 +10,12 @@ use num_enum::FromPrimitive;
 use crate::{Irqs, motor_controller};
 
 use super::handler::{HandlerCtx, handle_inbound_packet};
-use interface::{CurrentDraw, Interval, Motors, Speed, h2c};
+use interface::{CurrentDraw, Interval, Motors, Port, Speed, h2c};
 
 #[embassy_executor::task]
-pub async fn start_i2c(spawner: Spawner, i2c: I2C1, sda: PIN_19, scl: PIN_18) {
+pub async fn start_i2c(spawner: Spawner, i2c: I2C1, sda: PIN_19, scl: PIN_18, address: u8) {
     let mut config = Config::default();
-    config.addr = 0x42;
+    config.addr = address.into();
     config.general_call = false;
 
     let dev = I2cSlave::new(i2c, sda, scl, Irqs, config);
@@ -60,10 +60,13 @@ pub enum PacketsI2c {
     SetSpeed = 0,
     ReadMotor = 1,
     Arm = 2,
+    EmergencyStop = 3,
     #[num_enum(catch_all)]
     Unknown(u8),
 }
 
+/// I2C can not claim control and has no status for rejected commands, its speed and arm commands
+/// only take effect while no serial interface holds control
 async fn handle_message<Out: BufMut>(mut msg: impl Buf, mut response: Out) -> Out {
     let cmd = msg.get_u8();
 
@@ -72,7 +75,8 @@ async fn handle_message<Out: BufMut>(mut msg: impl Buf, mut response: Out) -> Ou
             let motors = Motors::from_bits_truncate(msg.get_u8());
             let speed = Speed(msg.get_i16());
 
-            handle_inbound_packet(&HandlerCtx::new(), h2c::SetSpeed { motors, speed }).await;
+            handle_inbound_packet(&HandlerCtx::new(Port::I2c), h2c::SetSpeed { motors, speed })
+                .await;
 
             let mut motor_controllers = motor_controller::MOTOR_CONTROLLERS.lock().await;
             if let Some(motor_controllers) = &mut *motor_controllers {
@@ -84,7 +88,7 @@ async fn handle_message<Out: BufMut>(mut msg: impl Buf, mut response: Out) -> Ou
 
                     response.put_u8(motor_id as u8);
                     response.put_u16(CurrentDraw::from_f32_amps(motor.current_draw()).0);
-                    response.put_u8(motor.is_fault() as u8);
+                    response.put_u8((motor.is_fault() || motor.is_overcurrent()) as u8);
                 }
             } else {
                 // Motor controllers are not initialized, write length of 0
@@ -105,7 +109,7 @@ async fn handle_message<Out: BufMut>(mut msg: impl Buf, mut response: Out) -> Ou
                     response.put_u8(motor_id as u8);
                     response.put_i16(Speed::from_f32(motor.last_speed()).0);
                     response.put_u16(CurrentDraw::from_f32_amps(motor.current_draw()).0);
-                    response.put_u8(motor.is_fault() as u8);
+                    response.put_u8((motor.is_fault() || motor.is_overcurrent()) as u8);
                 }
             } else {
                 // Motor controllers are not initialized, write length of 0
@@ -116,11 +120,18 @@ async fn handle_message<Out: BufMut>(mut msg: impl Buf, mut response: Out) -> Ou
             let duration = Interval(msg.get_u16());
 
             if duration.0 > 0 {
-                handle_inbound_packet(&HandlerCtx::new(), h2c::SetArmed::Armed { duration }).await;
+                handle_inbound_packet(
+                    &HandlerCtx::new(Port::I2c),
+                    h2c::SetArmed::Armed { duration },
+                )
+                .await;
             } else {
-                handle_inbound_packet(&HandlerCtx::new(), h2c::SetArmed::Disarmed).await;
+                handle_inbound_packet(&HandlerCtx::new(Port::I2c), h2c::SetArmed::Disarmed).await;
             }
         }
+        PacketsI2c::EmergencyStop => {
+            handle_inbound_packet(&HandlerCtx::new(Port::I2c), h2c::PacketH2C::EmergencyStop).await;
+        }
         PacketsI2c::Unknown(id) => {
             error!("Received unknown i2c packet id: {}", id);
         }
@@ -0,0 +1,78 @@
+//! Applies the [`DisconnectPolicy`] of a serial interface when the link to its host is lost
+
+use defmt::{Debug2Format, info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Instant, Timer};
+use interface::{DisconnectPolicy, Port};
+
+use crate::{config, motor_controller::MOTOR_CONTROLLERS, ownership, safety_watchdog};
+
+/// Latest connection state of every port, I2C has no link to lose
+static LINK_STATES: [Signal<CriticalSectionRawMutex, bool>; 3] = [const { Signal::new() }; 3];
+
+pub fn connected(port: Port) {
+    LINK_STATES[port as usize].signal(true);
+}
+
+pub fn disconnected(port: Port) {
+    LINK_STATES[port as usize].signal(false);
+}
+
+fn policy(port: Port) -> DisconnectPolicy {
+    let config = config::get();
+
+    match port {
+        Port::Usb => config.usb_disconnect,
+        Port::Uart => config.uart_disconnect,
+        Port::I2c => DisconnectPolicy::Ignore,
+    }
+}
+
+#[embassy_executor::task(pool_size = 2)]
+pub async fn watch_link(port: Port) {
+    let state = &LINK_STATES[port as usize];
+    let mut is_connected = false;
+
+    loop {
+        let was_connected = core::mem::replace(&mut is_connected, state.wait().await);
+        if is_connected || !was_connected {
+            continue;
+        }
+
+        // Nobody is left to send commands through it
+        ownership::release(port);
+
+        match policy(port) {
+            DisconnectPolicy::Ignore => info!("{} link lost", Debug2Format(&port)),
+            DisconnectPolicy::Disarm => disarm(port).await,
+            DisconnectPolicy::DisarmAfter(grace) => {
+                info!(
+                    "{} link lost, disarming unless it is back in time",
+                    Debug2Format(&port)
+                );
+                let deadline = Instant::now() + grace.as_duration();
+
+                loop {
+                    match select(Timer::at(deadline), state.wait()).await {
+                        Either::First(()) => {
+                            disarm(port).await;
+                            break;
+                        }
+                        Either::Second(true) => {
+                            info!("{} link back within the grace period", Debug2Format(&port));
+                            is_connected = true;
+                            break;
+                        }
+                        Either::Second(false) => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn disarm(port: Port) {
+    warn!("{} link lost, disarming every motor", Debug2Format(&port));
+    safety_watchdog::disarm(&mut MOTOR_CONTROLLERS.lock().await);
+}
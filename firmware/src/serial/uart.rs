@@ -1,36 +1,35 @@
 use defmt::{error, unwrap};
 use embassy_executor::Spawner;
 use embassy_rp::peripherals::{PIN_0, PIN_1, UART0};
-use embassy_rp::uart::{BufferedUart, BufferedUartRx, BufferedUartTx, Config};
+use embassy_rp::uart::{self, BufferedUart, BufferedUartRx, BufferedUartTx, Config};
+use embassy_time::with_timeout;
 use embedded_io_async::{Read, Write};
 use interface::decoder::PackerDecoder;
 use interface::encoder::encode_packet;
+use interface::{MAX_FRAME_SIZE, Port};
 use static_cell::StaticCell;
 
-use crate::Irqs;
+use crate::hardware_watchdog::{self, Task};
 use crate::serial::handler::stream_motor_data;
+use crate::serial::link;
+use crate::{Irqs, config};
 
 use super::handler::{HandlerCtx, feed_all_and_handle};
 
-static UART_CTX: HandlerCtx = HandlerCtx::new();
+pub static UART_CTX: HandlerCtx = HandlerCtx::new(Port::Uart);
 
 #[embassy_executor::task]
-pub async fn start_uart(spawner: Spawner, uart: UART0, tx_pin: PIN_0, rx_pin: PIN_1) {
+pub async fn start_uart(spawner: Spawner, uart: UART0, tx_pin: PIN_0, rx_pin: PIN_1, baud: u32) {
     static TX_BUF: StaticCell<[u8; 16]> = StaticCell::new();
     static RX_BUF: StaticCell<[u8; 16]> = StaticCell::new();
 
     let tx_buf = &mut TX_BUF.init([0; 16])[..];
     let rx_buf = &mut RX_BUF.init([0; 16])[..];
 
-    let uart = BufferedUart::new(
-        uart,
-        Irqs,
-        tx_pin,
-        rx_pin,
-        tx_buf,
-        rx_buf,
-        Config::default(),
-    );
+    let mut config = Config::default();
+    config.baudrate = baud;
+
+    let uart = BufferedUart::new(uart, Irqs, tx_pin, rx_pin, tx_buf, rx_buf, config);
 
     let (tx, rx) = uart.split();
 
@@ -38,11 +37,12 @@ pub async fn start_uart(spawner: Spawner, uart: UART0, tx_pin: PIN_0, rx_pin: PI
     unwrap!(spawner.spawn(uart_write_half(tx)));
     unwrap!(spawner.spawn(uart_read_half(rx)));
     unwrap!(spawner.spawn(stream_motor_data(&UART_CTX)));
+    unwrap!(spawner.spawn(link::watch_link(Port::Uart)));
 }
 
 #[embassy_executor::task]
 async fn uart_write_half(mut sender: BufferedUartTx<'static, UART0>) {
-    let mut buffer = [0; 128];
+    let mut buffer = [0; MAX_FRAME_SIZE];
 
     loop {
         let packet = UART_CTX.packets.receive().await;
@@ -64,21 +64,42 @@ async fn uart_write_half(mut sender: BufferedUartTx<'static, UART0>) {
 
 #[embassy_executor::task]
 async fn uart_read_half(mut receiver: BufferedUartRx<'static, UART0>) {
-    let mut decoder = PackerDecoder::<128>::new();
+    let mut decoder = PackerDecoder::<MAX_FRAME_SIZE>::new();
     // TODO: Try to get rid of the need for an extra buffer
     let mut buf = [0; 64];
 
     loop {
-        let res = receiver.read(&mut buf).await;
+        let idle_timeout = config::get().uart_idle_timeout;
+
+        let res = if idle_timeout.0 == 0 {
+            receiver.read(&mut buf).await
+        } else {
+            match with_timeout(idle_timeout.as_duration(), receiver.read(&mut buf)).await {
+                Ok(res) => res,
+                Err(_) => {
+                    link::disconnected(Port::Uart);
+                    continue;
+                }
+            }
+        };
+
         let n = match res {
             Ok(n) => n,
             Err(err) => {
                 error!("Uart rx error: {}", err);
                 decoder.reset();
+
+                // The line is held low, usually because the host side was unplugged
+                if matches!(err, uart::Error::Break) {
+                    link::disconnected(Port::Uart);
+                }
                 continue;
             }
         };
 
+        link::connected(Port::Uart);
+
+        let _busy = hardware_watchdog::busy(Task::UartRead);
         feed_all_and_handle(&buf[..n], &mut decoder, &UART_CTX).await;
     }
 }
@@ -1,17 +1,21 @@
 use defmt::{error, info, unwrap};
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
 use embassy_usb::UsbDevice;
-use embassy_usb::class::cdc_acm::{CdcAcmClass, Receiver, Sender, State};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, ControlChanged, Receiver, Sender, State};
 use interface::decoder::PackerDecoder;
 use interface::encoder::encode_packet;
+use interface::{MAX_FRAME_SIZE, Port};
 use static_cell::StaticCell;
 
 use crate::Irqs;
+use crate::hardware_watchdog::{self, Task};
 use crate::serial::handler::{HandlerCtx, feed_all_and_handle, stream_motor_data};
+use crate::serial::link;
 
-static USB_CTX: HandlerCtx = HandlerCtx::new();
+pub static USB_CTX: HandlerCtx = HandlerCtx::new(Port::Usb);
 
 #[embassy_executor::task]
 pub async fn start_usb(spawner: Spawner, usb: USB) {
@@ -56,13 +60,14 @@ pub async fn start_usb(spawner: Spawner, usb: USB) {
     // Build the builder.
     let usb = builder.build();
 
-    let (tx, rx) = class.split();
+    let (tx, rx, control) = class.split_with_control();
 
     // Run the USB device.
     unwrap!(spawner.spawn(usb_task(usb)));
     unwrap!(spawner.spawn(usb_write_half(tx)));
-    unwrap!(spawner.spawn(usb_read_half(rx)));
+    unwrap!(spawner.spawn(usb_read_half(rx, control)));
     unwrap!(spawner.spawn(stream_motor_data(&USB_CTX)));
+    unwrap!(spawner.spawn(link::watch_link(Port::Usb)));
 }
 
 type MyUsbDriver = Driver<'static, USB>;
@@ -75,7 +80,7 @@ async fn usb_task(mut usb: MyUsbDevice) -> ! {
 
 #[embassy_executor::task]
 async fn usb_write_half(mut sender: Sender<'static, MyUsbDriver>) {
-    let mut buffer = [0; 128];
+    let mut buffer = [0; MAX_FRAME_SIZE];
 
     loop {
         sender.wait_connection().await;
@@ -108,24 +113,48 @@ async fn usb_write_half(mut sender: Sender<'static, MyUsbDriver>) {
 }
 
 #[embassy_executor::task]
-async fn usb_read_half(mut receiver: Receiver<'static, MyUsbDriver>) {
-    let mut decoder = PackerDecoder::<128>::new();
+async fn usb_read_half(
+    mut receiver: Receiver<'static, MyUsbDriver>,
+    control: ControlChanged<'static>,
+) {
+    let mut decoder = PackerDecoder::<MAX_FRAME_SIZE>::new();
 
     loop {
         receiver.wait_connection().await;
 
         info!("USB read half connected");
+        update_link(&receiver);
 
         // TODO: Try to get rid of the need for an extra buffer
         let mut buf = [0; 64];
         loop {
-            let Ok(n) = receiver.read_packet(&mut buf).await else {
-                error!("Read packer error");
-                decoder.reset();
-                break;
+            let n = match select(receiver.read_packet(&mut buf), control.control_changed()).await {
+                Either::First(Ok(n)) => n,
+                Either::First(Err(_)) => {
+                    error!("Read packer error");
+                    decoder.reset();
+                    break;
+                }
+                Either::Second(()) => {
+                    update_link(&receiver);
+                    continue;
+                }
             };
 
+            let _busy = hardware_watchdog::busy(Task::UsbRead);
             feed_all_and_handle(&buf[..n], &mut decoder, &USB_CTX).await;
         }
+
+        // The cable was pulled or the host reset the device
+        link::disconnected(Port::Usb);
+    }
+}
+
+/// The host opening and closing the port sets and clears DTR
+fn update_link(receiver: &Receiver<'static, MyUsbDriver>) {
+    if receiver.dtr() {
+        link::connected(Port::Usb);
+    } else {
+        link::disconnected(Port::Usb);
     }
 }
@@ -0,0 +1,93 @@
+//! RP2040 die temperature, sampled by the ADC loop in [`crate::current`]
+
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use interface::Temperature;
+
+use crate::{motor_controller::MOTOR_CONTROLLERS, safety_watchdog};
+
+/// Weight of a new sample, the sensor is noisy and the die heats up slowly
+const FILTER_ALPHA: f32 = 0.001;
+/// How far the die has to cool below the limit before the motors can be armed again
+const HYSTERESIS: f32 = 5.0;
+
+#[derive(Clone, Copy)]
+struct State {
+    /// Filtered temperature in degrees Celsius, `None` until the first reading
+    celsius: Option<f32>,
+    /// Limit in degrees Celsius, `None` when disabled
+    limit: Option<f32>,
+    overheated: bool,
+}
+
+static STATE: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<State>> =
+    blocking_mutex::Mutex::new(Cell::new(State {
+        celsius: None,
+        limit: None,
+        overheated: false,
+    }));
+
+/// Low pass filtered die temperature
+pub fn temperature() -> Temperature {
+    STATE
+        .lock(|cell| cell.get().celsius)
+        .map_or(Temperature::NO_READING, Temperature::from_f32_celsius)
+}
+
+/// The die is above the thermal limit, motors must not be armed
+pub fn is_overheated() -> bool {
+    STATE.lock(|cell| cell.get().overheated)
+}
+
+/// Sets the temperature above which every motor is disarmed, zero disables the limit
+pub fn set_limit(limit: &Temperature) {
+    let limit = limit.as_f32_celsius().filter(|&limit| limit > 0.0);
+
+    STATE.lock(|cell| {
+        let mut state = cell.get();
+        state.limit = limit;
+        state.overheated &= limit.is_some();
+        cell.set(state);
+    });
+}
+
+/// Adds a raw reading of the temperature sensor channel, disarms every motor before returning
+/// when it crosses the limit
+pub(crate) async fn record(raw: u16) {
+    // Same reference as the current sense, conversion from the RP2040 datasheet
+    let voltage = raw as f32 / 4095.0 * 3.0;
+    let celsius = 27.0 - (voltage - 0.706) / 0.001721;
+
+    let (was_overheated, state) = STATE.lock(|cell| {
+        let mut state = cell.get();
+        let was_overheated = state.overheated;
+
+        let filtered = match state.celsius {
+            Some(filtered) => filtered + FILTER_ALPHA * (celsius - filtered),
+            None => celsius,
+        };
+        state.celsius = Some(filtered);
+
+        if let Some(limit) = state.limit {
+            if filtered > limit {
+                state.overheated = true;
+            } else if filtered < limit - HYSTERESIS {
+                state.overheated = false;
+            }
+        }
+
+        cell.set(state);
+        (was_overheated, state)
+    });
+
+    match (was_overheated, state.overheated) {
+        (false, true) => {
+            warn!("Die temperature above the thermal limit, disarming every motor");
+            safety_watchdog::disarm(&mut MOTOR_CONTROLLERS.lock().await);
+        }
+        (true, false) => info!("Die temperature back below the thermal limit"),
+        _ => {}
+    }
+}
@@ -2,68 +2,55 @@ use std::time::Duration;
 
 use anyhow::Context;
 use interface::{
-    Interval, Motors, Speed, h2c,
+    CurrentKind, Motors, Speed,
     implementation_tokio::{DcMotorController, DcMotorControllerHandle},
 };
-use tokio::sync::{broadcast, mpsc};
-use tracing::info;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt().init();
 
     let motor_controller = DcMotorController::open(DcMotorControllerHandle::FirstAvaible)
+        .await
         .context("Get motor controller interface")?;
 
-    let (tx_out, rx_out) = mpsc::channel(10);
-    let (tx_in, mut rx_in) = broadcast::channel(10);
-
-    let join_handle = tokio::spawn(motor_controller.start(tx_in, rx_out));
+    let (client, join_handle) = motor_controller.spawn();
 
+    let mut motor_state = client.subscribe_motor_state();
     tokio::spawn(async move {
-        while let Ok(packet) = rx_in.recv().await {
-            info!("Got packet: {packet:?}");
+        while let Ok(state) = motor_state.recv().await {
+            info!("Got motor state: {state:?}");
         }
     });
 
-    tx_out
-        .send(
-            h2c::StartStream {
-                motors: Motors::Mot0,
-                interval: Interval::from_duration(Duration::from_millis(500)),
-            }
-            .into(),
+    client
+        .start_stream(
+            Motors::Mot0,
+            Duration::from_millis(500),
+            CurrentKind::Average,
+            true,
         )
-        .await
-        .unwrap();
+        .await?;
 
-    tx_out.send(h2c::Ping { id: 42 }.into()).await.unwrap();
-    tx_out
-        .send(h2c::PacketH2C::ReadProtocolVersion)
-        .await
-        .unwrap();
-    // tx_out.send(h2c::PacketH2C::ResetToUsbBoot).await.unwrap();
+    let rtt = client.ping().await.context("Ping")?;
+    info!("Ping: {rtt:?}");
 
-    tx_out
-        .send(
-            h2c::SetArmed::Armed {
-                duration: Interval::from_duration(Duration::from_millis(1000)),
-            }
-            .into(),
-        )
-        .await
-        .unwrap();
+    let keepalive = client.keep_armed(Duration::from_millis(250), Duration::from_millis(100));
+    let mut keepalive_events = keepalive.subscribe_events();
+    tokio::spawn(async move {
+        while let Ok(event) = keepalive_events.recv().await {
+            warn!("Keepalive event: {event:?}");
+        }
+    });
 
-    tx_out
-        .send(
-            h2c::SetSpeed {
-                motors: Motors::all(),
-                speed: Speed::from_f32(0.5),
-            }
-            .into(),
-        )
-        .await
-        .unwrap();
+    client
+        .set_speed(Motors::all(), Speed::from_f32(0.5))
+        .await?;
+
+    tokio::signal::ctrl_c().await.context("Wait for ctrl-c")?;
+    keepalive.stop().await;
 
+    drop(client);
     join_handle.await.context("Motor Controller server")
 }
@@ -1,9 +1,16 @@
-use anyhow::Context;
+pub mod client;
+pub mod keepalive;
+pub mod params;
+
+use std::{error::Error, fmt, time::Duration};
+
+use anyhow::{Context, anyhow};
 use futures_util::{SinkExt, StreamExt};
 use postcard::de_flavors::crc::from_bytes_u16;
 use tokio::{
     select,
     sync::{broadcast, mpsc},
+    task::JoinHandle,
 };
 use tokio_serial::{SerialPortType, SerialStream, UsbPortInfo};
 use tokio_util::{
@@ -12,7 +19,12 @@ use tokio_util::{
 };
 use tracing::{error, info, warn};
 
-use crate::{CRC, c2h, encoder, h2c};
+use crate::{CRC, MAX_FRAME_SIZE, PROTOCOL_VERSION, c2h, encoder, h2c};
+
+use client::DcMotorClient;
+
+/// How long [`DcMotorController::open`] waits for the protocol version response
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct DcMotorController {
     inner: Framed<SerialStream, DcMotorControllerCodec>,
@@ -37,20 +49,77 @@ impl DcMotorController {
             .map(|it| it.port_name))
     }
 
-    pub fn open(stratagy: DcMotorControllerHandle) -> anyhow::Result<Self> {
+    /// Opens a motor controller and checks that it speaks the same protocol version as this library
+    pub async fn open(stratagy: DcMotorControllerHandle) -> Result<Self, OpenError> {
+        let mut motor_controller = Self::open_unchecked(stratagy)?;
+        motor_controller.handshake().await?;
+
+        Ok(motor_controller)
+    }
+
+    /// Opens a motor controller without checking its protocol version
+    pub fn open_unchecked(stratagy: DcMotorControllerHandle) -> Result<Self, OpenError> {
         let name = match stratagy {
-            DcMotorControllerHandle::FirstAvaible => Self::enumerate()?
+            DcMotorControllerHandle::FirstAvaible => Self::enumerate()
+                .map_err(OpenError::Serial)?
                 .next()
-                .context("No motor controller was found")?,
+                .ok_or(OpenError::NotFound)?,
             DcMotorControllerHandle::Name(name) => name,
         };
 
-        let serial = SerialStream::open(&tokio_serial::new(name, 115200))?;
+        let serial = SerialStream::open(&tokio_serial::new(name, 115200))
+            .map_err(|err| OpenError::Serial(err.into()))?;
         Ok(Self {
             inner: DcMotorControllerCodec.framed(serial),
         })
     }
 
+    async fn handshake(&mut self) -> Result<(), OpenError> {
+        self.inner
+            .send(&h2c::PacketH2C::ReadProtocolVersion)
+            .await
+            .map_err(OpenError::Serial)?;
+
+        let response = async {
+            loop {
+                match next_packet(&mut self.inner).await {
+                    Some(c2h::PacketC2H::ProtocolVersionResponse(response)) => {
+                        return Ok(response.version);
+                    }
+                    // Packets such as a stream left running by a previous session
+                    Some(_) => {}
+                    None => {
+                        return Err(OpenError::Serial(anyhow!("end of motor controller stream")));
+                    }
+                }
+            }
+        };
+
+        let version = tokio::time::timeout(HANDSHAKE_TIMEOUT, response)
+            .await
+            .map_err(|_| OpenError::HandshakeTimeout)??;
+
+        if version != PROTOCOL_VERSION {
+            return Err(OpenError::VersionMismatch {
+                host: PROTOCOL_VERSION,
+                controller: version,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Spawns the task driving this motor controller and returns a client connected to it
+    pub fn spawn(self) -> (DcMotorClient, JoinHandle<()>) {
+        let (tx_out, rx_out) = mpsc::channel(10);
+        let (tx_in, _) = broadcast::channel(32);
+
+        let client = DcMotorClient::new(tx_out, tx_in.clone());
+        let join_handle = tokio::spawn(self.start(tx_in, rx_out));
+
+        (client, join_handle)
+    }
+
     pub async fn start(
         self,
         inbound: broadcast::Sender<c2h::PacketC2H>,
@@ -60,20 +129,11 @@ impl DcMotorController {
 
         loop {
             select! {
-                inbound_frame = motor_controller.next() => {
+                inbound_frame = next_packet(&mut motor_controller) => {
                     if let Some(inbound_frame) = inbound_frame {
-                        match inbound_frame {
-                            Ok(inbound_frame) => {
-                                let res = inbound.send(inbound_frame);
-                                if res.is_err() {
-                                    info!("in channel disconnected");
-                                    break;
-                                }
-                            },
-                            Err(err) => {
-                                warn!("Error decoding packet: {err:?}");
-                            },
-                        }
+                        // Clients subscribe on demand, so having no receivers is not
+                        // a reason to stop. The task ends once the out channel closes
+                        let _ = inbound.send(inbound_frame);
                     } else {
                         info!("end of motor controller stream");
                         break;
@@ -99,11 +159,74 @@ impl DcMotorController {
     }
 }
 
+/// Next packet sent by the motor controller, skipping packets that fail to decode. `None` once the
+/// stream ended
+///
+/// Framed yields `None` once after a decoding error before resuming. It does so without waiting, so
+/// this stays cancel safe
+async fn next_packet(
+    inner: &mut Framed<SerialStream, DcMotorControllerCodec>,
+) -> Option<c2h::PacketC2H> {
+    let mut errored = false;
+
+    loop {
+        match inner.next().await {
+            Some(Ok(packet)) => return Some(packet),
+            Some(Err(err)) => {
+                warn!("Error decoding packet: {err:?}");
+                errored = true;
+            }
+            None if errored => errored = false,
+            None => return None,
+        }
+    }
+}
+
 pub enum DcMotorControllerHandle {
     FirstAvaible,
     Name(String),
 }
 
+#[derive(Debug)]
+pub enum OpenError {
+    /// No motor controller was found
+    NotFound,
+    /// The serial port could not be opened or used
+    Serial(anyhow::Error),
+    /// The motor controller did not report its protocol version in time
+    HandshakeTimeout,
+    /// The motor controller speaks an incompatible protocol version
+    VersionMismatch { host: u16, controller: u16 },
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenError::NotFound => write!(f, "No motor controller was found"),
+            OpenError::Serial(err) => write!(f, "Serial port error: {err}"),
+            OpenError::HandshakeTimeout => {
+                write!(
+                    f,
+                    "Timed out waiting for the motor controller protocol version"
+                )
+            }
+            OpenError::VersionMismatch { host, controller } => write!(
+                f,
+                "Protocol version mismatch, host speaks version {host} but the motor controller speaks version {controller}"
+            ),
+        }
+    }
+}
+
+impl Error for OpenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OpenError::Serial(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
 // FIXME: This type is implemented inefficiently
 pub struct DcMotorControllerCodec;
 
@@ -113,7 +236,7 @@ impl Decoder for DcMotorControllerCodec {
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Self::Item>> {
-        let mut buf = [0; 128];
+        let mut buf = [0; MAX_FRAME_SIZE];
 
         let null_byte = src.as_ref().iter().position(|b| *b == 0);
         if let Some(n) = null_byte {
@@ -131,7 +254,7 @@ impl Encoder<&h2c::PacketH2C> for DcMotorControllerCodec {
     type Error = anyhow::Error;
 
     fn encode(&mut self, item: &h2c::PacketH2C, dst: &mut BytesMut) -> anyhow::Result<()> {
-        let mut buf = [0; 128];
+        let mut buf = [0; MAX_FRAME_SIZE];
 
         let packet = encoder::encode_packet(item, &mut buf).context("Encode packet")?;
         dst.extend_from_slice(packet);
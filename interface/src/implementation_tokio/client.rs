@@ -0,0 +1,571 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::{self, Display},
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tracing::warn;
+
+use crate::{
+    Charge, ConfigParam, ConfigRejection, CurrentDraw, CurrentKind, DeviceConfig, Interval,
+    MotorMap, Motors, ParamValue, RampRate, Speed, c2h, h2c,
+};
+
+use super::{keepalive::ArmKeepalive, params::Param};
+
+/// How long request methods wait for a matching response by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientError {
+    /// The motor controller did not respond before the timeout elapsed
+    Timeout,
+    /// The task driving the motor controller has stopped
+    Disconnected,
+    /// The motor controller refused to read or write a setting
+    ConfigRejected(ConfigRejection),
+    /// The motor controller refused the request because a motor is armed
+    Armed,
+    /// Another interface holds control of the motor controller
+    NotOwner,
+    /// The token does not match the latched emergency stop
+    WrongToken,
+    /// The motor controller refused a command for a motor
+    Motor {
+        motor_id: u8,
+        fault: c2h::MotorFault,
+    },
+    /// The motor controller refused a command
+    Rejected(c2h::Error),
+}
+
+impl From<c2h::Error> for ClientError {
+    fn from(value: c2h::Error) -> Self {
+        match value {
+            c2h::Error::Armed => ClientError::Armed,
+            c2h::Error::NotOwner => ClientError::NotOwner,
+            c2h::Error::WrongToken => ClientError::WrongToken,
+            err => ClientError::Rejected(err),
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Timeout => write!(f, "Timed out waiting for the motor controller"),
+            ClientError::Disconnected => write!(f, "Motor controller disconnected"),
+            ClientError::Armed => write!(f, "Request refused while a motor is armed"),
+            ClientError::NotOwner => write!(f, "Another interface holds control"),
+            ClientError::WrongToken => write!(f, "Emergency stop token does not match"),
+            ClientError::ConfigRejected(reason) => {
+                write!(f, "Motor controller rejected the setting: {reason:?}")
+            }
+            ClientError::Motor { motor_id, fault } => {
+                write!(
+                    f,
+                    "Motor controller refused the command for motor {motor_id}: {fault:?}"
+                )
+            }
+            ClientError::Rejected(err) => {
+                write!(f, "Motor controller refused the command: {err:?}")
+            }
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+/// Cloneable handle used to talk to a motor controller driven by [`super::DcMotorController::start`]
+#[derive(Clone)]
+pub struct DcMotorClient {
+    outbound: mpsc::Sender<h2c::PacketH2C>,
+    inbound: broadcast::Sender<c2h::PacketC2H>,
+    next_ping_id: Arc<AtomicU8>,
+    timeout: Duration,
+}
+
+impl DcMotorClient {
+    pub fn new(
+        outbound: mpsc::Sender<h2c::PacketH2C>,
+        inbound: broadcast::Sender<c2h::PacketC2H>,
+    ) -> Self {
+        Self {
+            outbound,
+            inbound,
+            next_ping_id: Arc::new(AtomicU8::new(0)),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long request methods wait for their response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Subscribes to every packet sent by the motor controller
+    pub fn subscribe(&self) -> broadcast::Receiver<c2h::PacketC2H> {
+        self.inbound.subscribe()
+    }
+
+    pub fn subscribe_motor_state(&self) -> MotorStateSubscription {
+        MotorStateSubscription {
+            inner: self.subscribe(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Sends `packet` without waiting for the motor controller, see [`Self::command`]
+    pub async fn send(&self, packet: impl Into<h2c::PacketH2C>) -> Result<(), ClientError> {
+        self.outbound
+            .send(packet.into())
+            .await
+            .map_err(|_| ClientError::Disconnected)
+    }
+
+    pub async fn set_speed(&self, motors: Motors, speed: Speed) -> Result<(), ClientError> {
+        self.command(h2c::SetSpeed { motors, speed }).await
+    }
+
+    /// Sets the speed of every motor with a `Some` entry at the same instant, indexed by motor id
+    pub async fn set_speeds(&self, speeds: [Option<Speed>; 4]) -> Result<(), ClientError> {
+        self.command(h2c::SetSpeeds { speeds }).await
+    }
+
+    /// Limits how fast the applied speed of `motors` moves away from and towards zero, in full
+    /// scale per second. `f32::INFINITY` removes the limit
+    pub async fn set_ramp_rate(
+        &self,
+        motors: Motors,
+        acceleration: f32,
+        deceleration: f32,
+    ) -> Result<(), ClientError> {
+        self.command(h2c::SetRampRate {
+            motors,
+            acceleration: RampRate::from_f32(acceleration),
+            deceleration: RampRate::from_f32(deceleration),
+        })
+        .await
+    }
+
+    /// Cuts the output of `motors` once they draw more than `limit_amps` for `trip_time`
+    ///
+    /// A limit of zero disables the trip
+    pub async fn set_current_limit(
+        &self,
+        motors: Motors,
+        limit_amps: f32,
+        trip_time: Duration,
+    ) -> Result<(), ClientError> {
+        self.command(h2c::SetCurrentLimit {
+            motors,
+            limit: CurrentDraw::from_f32_amps(limit_amps),
+            trip_time: Interval::from_duration(trip_time),
+        })
+        .await
+    }
+
+    /// Holds the current of `motors` at `ceiling_amps` by lowering their duty cycle
+    ///
+    /// A ceiling of zero disables the foldback
+    pub async fn set_current_ceiling(
+        &self,
+        motors: Motors,
+        ceiling_amps: f32,
+    ) -> Result<(), ClientError> {
+        self.command(h2c::SetCurrentCeiling {
+            motors,
+            ceiling: CurrentDraw::from_f32_amps(ceiling_amps),
+        })
+        .await
+    }
+
+    /// Clears latched faults such as an overcurrent trip
+    pub async fn clear_faults(&self, motors: Motors) -> Result<(), ClientError> {
+        self.command(h2c::ClearFaults { motors }).await
+    }
+
+    /// Enables the motor outputs until `duration` elapses without another call to `arm`
+    pub async fn arm(&self, duration: Duration) -> Result<(), ClientError> {
+        self.command(h2c::SetArmed::Armed {
+            duration: Interval::from_duration(duration),
+        })
+        .await
+    }
+
+    /// Keeps the motor controller armed with `deadline` by re-arming it every `refresh`
+    ///
+    /// The motors are disarmed once the returned handle is dropped or stopped, or at the latest once
+    /// `deadline` elapses after the last refresh, see [`ArmKeepalive`]
+    pub fn keep_armed(&self, deadline: Duration, refresh: Duration) -> ArmKeepalive {
+        ArmKeepalive::spawn(self.outbound.clone(), deadline, refresh)
+    }
+
+    pub async fn disarm(&self) -> Result<(), ClientError> {
+        self.command(h2c::SetArmed::Disarmed).await
+    }
+
+    /// Starts streaming the state of `motors` with `current` as their current draw, an interval of
+    /// zero stops the stream
+    ///
+    /// With `charge` set the states also carry the charge drawn by each motor
+    pub async fn start_stream(
+        &self,
+        motors: Motors,
+        interval: Duration,
+        current: CurrentKind,
+        charge: bool,
+    ) -> Result<(), ClientError> {
+        self.command(h2c::StartStream {
+            motors,
+            interval: Interval::from_duration(interval),
+            current,
+            charge,
+        })
+        .await
+    }
+
+    /// Measures the round trip time to the motor controller
+    pub async fn ping(&self) -> Result<Duration, ClientError> {
+        let id = self.next_ping_id.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+
+        self.request(h2c::Ping { id }, |packet| match packet {
+            c2h::PacketC2H::Pong(pong) if pong.id == id => Some(()),
+            _ => None,
+        })
+        .await?;
+
+        Ok(start.elapsed())
+    }
+
+    pub async fn protocol_version(&self) -> Result<u16, ClientError> {
+        self.request(h2c::PacketH2C::ReadProtocolVersion, |packet| match packet {
+            c2h::PacketC2H::ProtocolVersionResponse(response) => Some(response.version),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn software_data(&self) -> Result<c2h::SoftwareDataResponse, ClientError> {
+        self.request(h2c::PacketH2C::ReadSoftwareData, |packet| match packet {
+            c2h::PacketC2H::SoftwareDataResponse(response) => Some(response.clone()),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Remaps the logical motors onto the physical outputs, disarming every motor
+    pub async fn set_motor_map(&self, map: MotorMap) -> Result<(), ClientError> {
+        self.command(h2c::SetMotorMap { map }).await
+    }
+
+    pub async fn motor_map(&self) -> Result<MotorMap, ClientError> {
+        self.request(h2c::PacketH2C::ReadMotorMap, |packet| match packet {
+            c2h::PacketC2H::MotorMapResponse(response) => Some(response.map),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Configuration in use, which may differ from the one persisted in flash
+    pub async fn config(&self) -> Result<DeviceConfig, ClientError> {
+        self.request(h2c::PacketH2C::ReadConfig, |packet| match packet {
+            c2h::PacketC2H::ConfigResponse(response) => Some(response.config.clone()),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Replaces the configuration in use without persisting it, see [`Self::commit_config`]
+    pub async fn write_config(&self, config: DeviceConfig) -> Result<(), ClientError> {
+        self.command(h2c::WriteConfig { config }).await
+    }
+
+    /// Persists the configuration in use to flash, refused while a motor is armed
+    pub async fn commit_config(&self) -> Result<(), ClientError> {
+        self.command(h2c::PacketH2C::CommitConfig).await
+    }
+
+    /// Erases the persisted configuration and restores the defaults, refused while a motor is armed
+    pub async fn factory_reset(&self) -> Result<(), ClientError> {
+        self.command(h2c::PacketH2C::FactoryReset).await
+    }
+
+    /// Reads a single setting of the configuration in use
+    pub async fn get_config(&self, param: ConfigParam) -> Result<ParamValue, ClientError> {
+        self.request(h2c::GetConfig { param }, |packet| {
+            config_response(packet, param)
+        })
+        .await?
+    }
+
+    /// Changes a single setting of the configuration in use, returning its new value
+    ///
+    /// The change is only persisted by [`Self::commit_config`]
+    pub async fn set_config(
+        &self,
+        param: ConfigParam,
+        value: ParamValue,
+    ) -> Result<ParamValue, ClientError> {
+        self.request(h2c::SetConfig { param, value }, |packet| {
+            config_response(packet, param)
+        })
+        .await?
+    }
+
+    pub async fn get_param<P: Param>(&self, param: P) -> Result<P::Value, ClientError> {
+        let value = self.get_config(param.id()).await?;
+
+        P::from_value(value).ok_or(ClientError::ConfigRejected(ConfigRejection::WrongType))
+    }
+
+    /// Changes a single setting of the configuration in use, returning its new value
+    ///
+    /// The change is only persisted by [`Self::commit_config`]
+    pub async fn set_param<P: Param>(
+        &self,
+        param: P,
+        value: P::Value,
+    ) -> Result<P::Value, ClientError> {
+        let value = self.set_config(param.id(), P::into_value(value)).await?;
+
+        P::from_value(value).ok_or(ClientError::ConfigRejected(ConfigRejection::WrongType))
+    }
+
+    /// Measures the zero current offset of every physical output, refused while a motor is armed
+    ///
+    /// The offsets are kept until the next calibration, they are measured again at every boot
+    pub async fn calibrate_current(&self) -> Result<[u16; 4], ClientError> {
+        self.request(h2c::PacketH2C::CalibrateCurrent, |packet| match packet {
+            c2h::PacketC2H::CurrentCalibrated(response) => Some(Ok(response.offsets)),
+            c2h::PacketC2H::Error(err @ (c2h::Error::Armed | c2h::Error::NotOwner)) => {
+                Some(Err(ClientError::from(*err)))
+            }
+            _ => None,
+        })
+        .await?
+    }
+
+    /// Charge drawn by every motor since its counter was last reset, indexed by motor id
+    pub async fn charge(&self) -> Result<[Charge; 4], ClientError> {
+        self.request(h2c::PacketH2C::ReadCharge, |packet| match packet {
+            c2h::PacketC2H::ChargeResponse(response) => Some(response.charge),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn reset_charge(&self, motors: Motors) -> Result<(), ClientError> {
+        self.command(h2c::ResetCharge { motors }).await
+    }
+
+    pub async fn device_status(&self) -> Result<c2h::DeviceStatus, ClientError> {
+        self.request(h2c::PacketH2C::ReadDeviceStatus, |packet| match packet {
+            c2h::PacketC2H::DeviceStatus(status) => Some(status.clone()),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Takes control so motion and arming commands from other interfaces are rejected
+    ///
+    /// Fails with [`ClientError::NotOwner`] while an interface of equal or higher priority holds
+    /// control
+    pub async fn claim_control(&self) -> Result<(), ClientError> {
+        let status = self
+            .request(h2c::PacketH2C::ClaimControl, |packet| match packet {
+                c2h::PacketC2H::ControlStatus(status) => Some(status.clone()),
+                _ => None,
+            })
+            .await?;
+
+        if !status.is_owner {
+            return Err(ClientError::NotOwner);
+        }
+
+        Ok(())
+    }
+
+    pub async fn release_control(&self) -> Result<(), ClientError> {
+        self.command(h2c::PacketH2C::ReleaseControl).await
+    }
+
+    pub async fn control_status(&self) -> Result<c2h::ControlStatus, ClientError> {
+        self.request(h2c::PacketH2C::ReadControlStatus, |packet| match packet {
+            c2h::PacketC2H::ControlStatus(status) => Some(status.clone()),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Disarms every motor until [`Self::clear_emergency_stop`] is called, even across resets
+    pub async fn emergency_stop(&self) -> Result<(), ClientError> {
+        self.command(h2c::PacketH2C::EmergencyStop).await
+    }
+
+    /// `token` is reported by [`c2h::EmergencyStopEvent`] and [`Self::device_status`]
+    pub async fn clear_emergency_stop(&self, token: u32) -> Result<(), ClientError> {
+        self.request(h2c::ClearEmergencyStop { token }, |packet| match packet {
+            c2h::PacketC2H::EmergencyStopEvent(event) if !event.latched => Some(Ok(())),
+            c2h::PacketC2H::Error(c2h::Error::WrongToken) => Some(Err(ClientError::WrongToken)),
+            c2h::PacketC2H::Error(c2h::Error::NotOwner) => Some(Err(ClientError::NotOwner)),
+            _ => None,
+        })
+        .await?
+    }
+
+    /// Sets how often the firmware control loop applies the motor setpoints
+    pub async fn set_control_loop_rate(&self, rate_hz: u16) -> Result<(), ClientError> {
+        self.command(h2c::SetControlLoopRate { rate_hz }).await
+    }
+
+    pub async fn control_loop_stats(&self) -> Result<c2h::ControlLoopStats, ClientError> {
+        self.request(
+            h2c::PacketH2C::ReadControlLoopStats,
+            |packet| match packet {
+                c2h::PacketC2H::ControlLoopStats(stats) => Some(stats.clone()),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn reset_control_loop_stats(&self) -> Result<(), ClientError> {
+        self.command(h2c::PacketH2C::ResetControlLoopStats).await
+    }
+
+    /// Sends `packet` and waits until the motor controller handled it, failing with the first error
+    /// it reported in the meantime
+    ///
+    /// Commands are not acknowledged, a ping sent right after is answered once the command was
+    /// handled. Errors caused by other clones of this client sending at the same time can end up
+    /// attributed to this command
+    pub async fn command(&self, packet: impl Into<h2c::PacketH2C>) -> Result<(), ClientError> {
+        let id = self.next_ping_id.fetch_add(1, Ordering::Relaxed);
+
+        // Subscribe before sending so no error can be missed
+        let mut inbound = self.subscribe();
+        self.send(packet).await?;
+        self.send(h2c::Ping { id }).await?;
+
+        let mut error = None;
+        self.wait_for(&mut inbound, |packet| match packet {
+            c2h::PacketC2H::Pong(pong) if pong.id == id => Some(error.take()),
+            c2h::PacketC2H::Error(err) => {
+                error.get_or_insert(ClientError::from(*err));
+                None
+            }
+            c2h::PacketC2H::MotorError(err) => {
+                error.get_or_insert(ClientError::Motor {
+                    motor_id: err.motor_id,
+                    fault: err.fault,
+                });
+                None
+            }
+            _ => None,
+        })
+        .await?
+        .map_or(Ok(()), Err)
+    }
+
+    /// Sends `packet` and resolves with the first inbound packet accepted by `matcher`
+    pub async fn request<T>(
+        &self,
+        packet: impl Into<h2c::PacketH2C>,
+        matcher: impl FnMut(&c2h::PacketC2H) -> Option<T>,
+    ) -> Result<T, ClientError> {
+        // Subscribe before sending so the response cannot be missed
+        let mut inbound = self.subscribe();
+        self.send(packet).await?;
+
+        self.wait_for(&mut inbound, matcher).await
+    }
+
+    /// Resolves with the first packet of `inbound` accepted by `matcher`
+    async fn wait_for<T>(
+        &self,
+        inbound: &mut broadcast::Receiver<c2h::PacketC2H>,
+        mut matcher: impl FnMut(&c2h::PacketC2H) -> Option<T>,
+    ) -> Result<T, ClientError> {
+        let response = async {
+            loop {
+                match inbound.recv().await {
+                    Ok(packet) => {
+                        if let Some(value) = matcher(&packet) {
+                            return Ok(value);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Client lagged behind by {skipped} packets");
+                    }
+                    Err(RecvError::Closed) => return Err(ClientError::Disconnected),
+                }
+            }
+        };
+
+        tokio::time::timeout(self.timeout, response)
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+}
+
+/// Matches the answer to a [`h2c::GetConfig`] or [`h2c::SetConfig`] of `param`
+fn config_response(
+    packet: &c2h::PacketC2H,
+    param: ConfigParam,
+) -> Option<Result<ParamValue, ClientError>> {
+    match packet {
+        c2h::PacketC2H::ConfigValue(response) if response.param == param => {
+            Some(Ok(response.value.clone()))
+        }
+        c2h::PacketC2H::ConfigRejected(response) if response.param == param => {
+            Some(Err(ClientError::ConfigRejected(response.reason)))
+        }
+        _ => None,
+    }
+}
+
+/// Receives the per-motor updates sent by the motor controller
+///
+/// Batched `MotorStates` packets are unpacked into one update per motor
+pub struct MotorStateSubscription {
+    inner: broadcast::Receiver<c2h::PacketC2H>,
+    pending: VecDeque<c2h::MotorState>,
+}
+
+impl MotorStateSubscription {
+    pub async fn recv(&mut self) -> Result<c2h::MotorState, ClientError> {
+        loop {
+            if let Some(state) = self.pending.pop_front() {
+                return Ok(state);
+            }
+
+            match self.inner.recv().await {
+                Ok(c2h::PacketC2H::MotorState(state)) => return Ok(state),
+                Ok(c2h::PacketC2H::MotorStates(states)) => {
+                    self.pending.extend(states.states.into_iter().flatten());
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Motor state subscription lagged behind by {skipped} packets");
+                }
+                Err(RecvError::Closed) => return Err(ClientError::Disconnected),
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::{self, Instant, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{Interval, h2c};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeepaliveEvent {
+    /// More time than the arming deadline passed between two refreshes, so the motor
+    /// controller has likely disarmed in the meantime
+    MissedRefresh { since_last: Duration },
+    /// The motor controller task stopped, no more refreshes will be sent
+    Disconnected,
+}
+
+/// Keeps the motor controller armed until dropped or stopped
+///
+/// Dropping the handle queues a `SetArmed::Disarmed` packet. Prefer [`ArmKeepalive::stop`]
+/// during shutdown as it waits until the packet was handed to the motor controller task
+///
+/// Disarming on shutdown is best effort. The packet is lost when the outbound queue is full or the
+/// motor controller task is already gone, and nothing is sent when the process is killed. The
+/// motor controller then disarms once the `deadline` elapses, keep it short
+pub struct ArmKeepalive {
+    outbound: mpsc::Sender<h2c::PacketH2C>,
+    events: broadcast::Sender<KeepaliveEvent>,
+    cancel: CancellationToken,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl ArmKeepalive {
+    /// Re-arms the motor controller with `deadline` every `refresh`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `refresh` is not shorter than `deadline`
+    pub(super) fn spawn(
+        outbound: mpsc::Sender<h2c::PacketH2C>,
+        deadline: Duration,
+        refresh: Duration,
+    ) -> Self {
+        assert!(
+            refresh < deadline,
+            "Keepalive refresh period must be shorter than the arming deadline"
+        );
+
+        let (events, _) = broadcast::channel(8);
+        let cancel = CancellationToken::new();
+
+        let join_handle = tokio::spawn(run_keepalive(
+            outbound.clone(),
+            events.clone(),
+            cancel.clone(),
+            deadline,
+            refresh,
+        ));
+
+        Self {
+            outbound,
+            events,
+            cancel,
+            join_handle: Some(join_handle),
+        }
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<KeepaliveEvent> {
+        self.events.subscribe()
+    }
+
+    /// Stops refreshing and waits for the disarm packet to be queued
+    pub async fn stop(mut self) {
+        self.cancel.cancel();
+
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.await;
+        }
+    }
+}
+
+impl Drop for ArmKeepalive {
+    fn drop(&mut self) {
+        if let Some(join_handle) = self.join_handle.take() {
+            self.cancel.cancel();
+
+            // The keepalive task also disarms once it sees the cancellation, but it
+            // will never get the chance if the runtime is shutting down
+            let _ = self.outbound.try_send(h2c::SetArmed::Disarmed.into());
+            drop(join_handle);
+        }
+    }
+}
+
+async fn run_keepalive(
+    outbound: mpsc::Sender<h2c::PacketH2C>,
+    events: broadcast::Sender<KeepaliveEvent>,
+    cancel: CancellationToken,
+    deadline: Duration,
+    refresh: Duration,
+) {
+    let mut interval = time::interval(refresh);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut last_refresh = None::<Instant>;
+
+    loop {
+        select! {
+            _ = cancel.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        let now = Instant::now();
+        if let Some(last_refresh) = last_refresh {
+            let since_last = now - last_refresh;
+
+            if since_last > deadline {
+                warn!(
+                    "Arming keepalive missed its deadline by {:?}",
+                    since_last - deadline
+                );
+                let _ = events.send(KeepaliveEvent::MissedRefresh { since_last });
+            }
+        }
+
+        let packet = h2c::SetArmed::Armed {
+            duration: Interval::from_duration(deadline),
+        };
+
+        if outbound.send(packet.into()).await.is_err() {
+            info!("Arming keepalive stopped, motor controller disconnected");
+            let _ = events.send(KeepaliveEvent::Disconnected);
+            return;
+        }
+
+        last_refresh = Some(now);
+    }
+
+    let _ = outbound.send(h2c::SetArmed::Disarmed.into()).await;
+}
@@ -0,0 +1,139 @@
+//! Typed handles for the settings of the [`DeviceConfig`](crate::DeviceConfig)
+//!
+//! Used with [`DcMotorClient::get_param`] and [`DcMotorClient::set_param`], tools listing every
+//! setting can use [`ConfigParam::all`] with the untyped [`DcMotorClient::get_config`] instead
+//!
+//! [`DcMotorClient::get_param`]: super::client::DcMotorClient::get_param
+//! [`DcMotorClient::set_param`]: super::client::DcMotorClient::set_param
+//! [`DcMotorClient::get_config`]: super::client::DcMotorClient::get_config
+
+use crate::{
+    ConfigParam, CurrentDraw, DisconnectPolicy, FixedString, Interval, ParamValue, RampRate,
+    Temperature,
+};
+
+pub trait Param {
+    type Value;
+
+    fn id(&self) -> ConfigParam;
+    fn into_value(value: Self::Value) -> ParamValue;
+    /// `None` when `value` does not have the type of this setting
+    fn from_value(value: ParamValue) -> Option<Self::Value>;
+}
+
+macro_rules! param {
+    ($(#[$meta:meta])* $name:ident, $variant:ident($value:ty)) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name;
+
+        impl Param for $name {
+            type Value = $value;
+
+            fn id(&self) -> ConfigParam {
+                ConfigParam::$name
+            }
+
+            param!(@convert $variant($value));
+        }
+    };
+    ($(#[$meta:meta])* $name:ident($index:ident), $variant:ident($value:ty)) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name(pub u8);
+
+        impl Param for $name {
+            type Value = $value;
+
+            fn id(&self) -> ConfigParam {
+                ConfigParam::$name(self.0)
+            }
+
+            param!(@convert $variant($value));
+        }
+    };
+    (@convert $variant:ident($value:ty)) => {
+        fn into_value(value: $value) -> ParamValue {
+            ParamValue::$variant(value)
+        }
+
+        fn from_value(value: ParamValue) -> Option<$value> {
+            match value {
+                ParamValue::$variant(value) => Some(value),
+                _ => None,
+            }
+        }
+    };
+}
+
+param!(
+    /// Changing the motor map disarms every motor
+    MotorMap,
+    MotorMap(crate::MotorMap)
+);
+param!(
+    /// Overcurrent trip level of a motor, zero disables the trip
+    CurrentLimit(motor_id),
+    Current(CurrentDraw)
+);
+param!(
+    /// How long a motor may exceed its current limit before the trip latches
+    TripTime(motor_id),
+    Interval(Interval)
+);
+param!(
+    /// Current held by the foldback of a motor, zero disables the foldback
+    CurrentCeiling(motor_id),
+    Current(CurrentDraw)
+);
+param!(Acceleration(motor_id), RampRate(RampRate));
+param!(Deceleration(motor_id), RampRate(RampRate));
+param!(
+    /// What a motor does once the arming deadline elapses
+    Failsafe(motor_id),
+    Failsafe(crate::Failsafe)
+);
+param!(
+    /// Applied on the next boot
+    I2cAddress,
+    U8(u8)
+);
+param!(
+    /// Applied on the next boot
+    UartBaud,
+    U32(u32)
+);
+param!(DeviceName, String(FixedString));
+param!(
+    /// Current scale of a physical output in ten thousandths, 10000 is unity
+    CurrentGain(output),
+    U16(u16)
+);
+param!(
+    /// Time constant of the current average and RMS filters, zero disables the filtering
+    CurrentFilter,
+    Interval(Interval)
+);
+param!(
+    /// Die temperature above which every motor is disarmed, zero disables the limit
+    ThermalLimit,
+    Temperature(Temperature)
+);
+param!(UsbDisconnect, DisconnectPolicy(DisconnectPolicy));
+param!(UartDisconnect, DisconnectPolicy(DisconnectPolicy));
+param!(
+    /// Zero disables the idle detection, a break condition still counts as a lost link
+    UartIdleTimeout,
+    Interval(Interval)
+);
+param!(
+    /// Takeover priority of a [`Port`](crate::Port), indexed by the port. I2C can not claim
+    /// control and has none
+    PortPriority(port),
+    U8(u8)
+);
+param!(
+    /// How often the control loop applies the motor setpoints, in Hz
+    ControlLoopRate,
+    U16(u16)
+);
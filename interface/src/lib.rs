@@ -5,6 +5,8 @@ pub mod encoder;
 #[cfg(all(feature = "std", feature = "implementation_tokio"))]
 pub mod implementation_tokio;
 
+use core::ops::RangeInclusive;
+
 use bitflags::bitflags;
 
 use crc::{Crc, Table};
@@ -16,12 +18,22 @@ use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
-pub const PROTOCOL_VERSION: u16 = 2;
+/// Major protocol version, bumped whenever the layout or meaning of an existing packet changes
+///
+/// Hosts refuse to talk to motor controllers reporting a different version
+pub const PROTOCOL_VERSION: u16 = 19;
 
 pub const CRC: Crc<u16, Table<1>> = Crc::<u16>::new(&crc::CRC_16_USB);
 
+/// Size of the buffers holding an encoded frame
+pub const MAX_FRAME_SIZE: usize = 256;
+
+// The CRC adds 2 bytes, COBS adds 1 byte and the delimiter for packets shorter than 254 bytes
+const _: () = assert!(h2c::PacketH2C::POSTCARD_MAX_SIZE + 4 <= MAX_FRAME_SIZE);
+const _: () = assert!(c2h::PacketC2H::POSTCARD_MAX_SIZE + 4 <= MAX_FRAME_SIZE);
+
 bitflags! {
-    #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
     pub struct Motors: u8 {
         const Mot0 = 0b00000001;
         const Mot1 = 0b00000010;
@@ -34,6 +46,41 @@ impl MaxSize for Motors {
     const POSTCARD_MAX_SIZE: usize = 1;
 }
 
+/// Maps logical motors onto the physical outputs of the board
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct MotorMap {
+    /// Physical output driven by each logical motor, must be a permutation of `0..4`
+    pub outputs: [u8; 4],
+    /// Logical motors whose direction is reversed
+    pub inverted: Motors,
+}
+
+impl MotorMap {
+    pub const IDENTITY: Self = Self {
+        outputs: [0, 1, 2, 3],
+        inverted: Motors::empty(),
+    };
+
+    pub fn is_valid(&self) -> bool {
+        let mut seen = [false; 4];
+
+        for &output in &self.outputs {
+            match seen.get_mut(output as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return false,
+            }
+        }
+
+        true
+    }
+}
+
+impl Default for MotorMap {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
 pub struct Speed(pub i16);
 
@@ -47,24 +94,127 @@ impl Speed {
     }
 }
 
+/// Current in milliamps
+///
+/// The two highest values are reserved, see [`CurrentDraw::NO_READING`] and
+/// [`CurrentDraw::SATURATED`]
 #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
 pub struct CurrentDraw(pub u16);
 
 impl CurrentDraw {
+    /// No measurement is available
+    pub const NO_READING: Self = Self(u16::MAX);
+    /// The current is above the highest value that can be represented
+    pub const SATURATED: Self = Self(u16::MAX - 1);
+
+    /// Negative values mean no reading
     pub fn from_f32_amps(amps: f32) -> Self {
         if amps < 0.0 {
-            return Self(u16::MAX);
+            return Self::NO_READING;
         }
 
-        Self((amps.clamp(0.0, 3.0) / 3.0 * (u16::MAX - 1) as f32) as u16)
+        // Rounds to the nearest milliamp, `amps` is known to be positive
+        let milliamps = amps * 1000.0 + 0.5;
+        if milliamps >= Self::SATURATED.0 as f32 {
+            return Self::SATURATED;
+        }
+
+        Self(milliamps as u16)
     }
 
+    /// Negative when there is no reading, saturated currents report the lowest saturated value
     pub fn as_f32_amps(&self) -> f32 {
-        if self.0 == u16::MAX {
+        if self.is_no_reading() {
             return -1.0;
         }
 
-        3.0 * self.0 as f32 / (u16::MAX - 1) as f32
+        self.0 as f32 / 1000.0
+    }
+
+    pub fn is_no_reading(&self) -> bool {
+        self.0 == Self::NO_READING.0
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.0 == Self::SATURATED.0
+    }
+}
+
+/// Temperature in tenths of a degree Celsius, the lowest value is reserved, see
+/// [`Temperature::NO_READING`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Temperature(pub i16);
+
+impl Temperature {
+    /// No measurement is available
+    pub const NO_READING: Self = Self(i16::MIN);
+
+    pub fn from_f32_celsius(celsius: f32) -> Self {
+        // Rounds to the nearest tenth, the cast saturates
+        let tenths = celsius * 10.0 + if celsius < 0.0 { -0.5 } else { 0.5 };
+
+        Self((tenths as i16).max(i16::MIN + 1))
+    }
+
+    /// `None` when there is no reading
+    pub fn as_f32_celsius(&self) -> Option<f32> {
+        if self.is_no_reading() {
+            return None;
+        }
+
+        Some(self.0 as f32 / 10.0)
+    }
+
+    pub fn is_no_reading(&self) -> bool {
+        self.0 == Self::NO_READING.0
+    }
+}
+
+/// Rate of change of a speed in thousandths of full scale per second, zero means unlimited
+#[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+pub struct RampRate(pub u16);
+
+impl RampRate {
+    pub const UNLIMITED: Self = Self(0);
+
+    pub fn from_f32(full_scale_per_sec: f32) -> Self {
+        if full_scale_per_sec.is_infinite() {
+            return Self::UNLIMITED;
+        }
+
+        Self((full_scale_per_sec * 1000.0).clamp(1.0, u16::MAX as f32) as u16)
+    }
+
+    /// Full scale per second, `None` when unlimited
+    pub fn as_f32(&self) -> Option<f32> {
+        if self.0 == 0 {
+            return None;
+        }
+
+        Some(self.0 as f32 / 1000.0)
+    }
+}
+
+/// Which current measurement a report carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum CurrentKind {
+    /// Latest sample, includes the PWM ripple
+    Instantaneous,
+    /// Low pass filtered, see [`DeviceConfig::current_filter`]
+    Average,
+    /// Root mean square, filtered like the average
+    Rms,
+    /// Highest sample since the last report on the same interface
+    Peak,
+}
+
+/// Charge drawn in microamp hours, saturates instead of wrapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, MaxSize)]
+pub struct Charge(pub u32);
+
+impl Charge {
+    pub fn as_f32_amp_hours(&self) -> f32 {
+        self.0 as f32 / 1e6
     }
 }
 
@@ -81,12 +231,470 @@ impl Interval {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct SemanticVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+/// Nul padded UTF-8 string with a fixed size on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, MaxSize)]
+pub struct FixedString(pub [u8; 16]);
+
+impl FixedString {
+    /// Creates a new string, truncating `value` to the longest prefix that fits
+    pub fn new(value: &str) -> Self {
+        let mut len = value.len().min(16);
+        while !value.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut bytes = [0; 16];
+        bytes[..len].copy_from_slice(&value.as_bytes()[..len]);
+
+        Self(bytes)
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(16);
+
+        core::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+}
+
+/// What a motor does once the [`h2c::SetArmed`] deadline elapses
+#[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+pub enum Failsafe {
+    /// The driver output is released and the motor spins down freely
+    Coast,
+    /// The motor terminals are shorted for a second, which resists motion, then it coasts
+    Brake,
+    /// Keeps driving the motor at `speed` for `duration`, then brakes
+    Hold { speed: Speed, duration: Interval },
+}
+
+impl Failsafe {
+    /// Fastest a motor may hold at, a quarter of full scale
+    pub const MAX_HOLD_SPEED: Speed = Speed(i16::MAX / 4);
+    /// Longest a motor may hold for
+    pub const MAX_HOLD_DURATION: Interval = Interval(5000);
+
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Failsafe::Coast | Failsafe::Brake => true,
+            Failsafe::Hold { speed, duration } => {
+                speed.0.unsigned_abs() <= Self::MAX_HOLD_SPEED.0.unsigned_abs()
+                    && duration.0 <= Self::MAX_HOLD_DURATION.0
+            }
+        }
+    }
+}
+
+/// Settings of a single motor stored in the [`DeviceConfig`]
+#[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+pub struct MotorConfig {
+    /// Overcurrent trip level, zero disables the trip
+    pub current_limit: CurrentDraw,
+    pub trip_time: Interval,
+    /// Current held by the foldback, zero disables the foldback
+    pub current_ceiling: CurrentDraw,
+    pub acceleration: RampRate,
+    pub deceleration: RampRate,
+    pub failsafe: Failsafe,
+}
+
+impl MotorConfig {
+    pub const DEFAULT: Self = Self {
+        current_limit: CurrentDraw(0),
+        trip_time: Interval(0),
+        current_ceiling: CurrentDraw(0),
+        acceleration: RampRate::UNLIMITED,
+        deceleration: RampRate::UNLIMITED,
+        failsafe: Failsafe::Coast,
+    };
+}
+
+impl Default for MotorConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// What the motor controller does when the link to a host is lost
+#[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+pub enum DisconnectPolicy {
+    /// Motors stay armed until the [`h2c::SetArmed`] deadline elapses
+    Ignore,
+    /// Every motor is disarmed as soon as the link is lost
+    Disarm,
+    /// Every motor is disarmed unless the link comes back within the grace period
+    DisarmAfter(Interval),
+}
+
+/// Interface a host is connected through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum Port {
+    Usb,
+    Uart,
+    I2c,
+}
+
+impl Port {
+    pub const ALL: [Port; 3] = [Port::Usb, Port::Uart, Port::I2c];
+    /// Ports that can claim control, I2C can not
+    pub const SERIAL: [Port; 2] = [Port::Usb, Port::Uart];
+}
+
+/// Settings the motor controller persists in flash, indexed by logical motor id
+#[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+pub struct DeviceConfig {
+    pub motor_map: MotorMap,
+    pub motors: [MotorConfig; 4],
+    /// Scale applied to the sensed current in ten thousandths, indexed by physical output. The zero
+    /// offsets are measured at boot and not persisted
+    pub current_gain: [u16; 4],
+    /// Time constant of the current average and RMS filters, zero disables the filtering
+    pub current_filter: Interval,
+    /// 7 bit address of the I2C interface, applied on the next boot
+    pub i2c_address: u8,
+    /// Baud rate of the UART interface, applied on the next boot
+    pub uart_baud: u32,
+    /// Empty when the device has not been named
+    pub device_name: FixedString,
+    /// Die temperature above which every motor is disarmed, zero disables the limit
+    pub thermal_limit: Temperature,
+    /// Applied when the USB cable is pulled or the host deasserts DTR
+    pub usb_disconnect: DisconnectPolicy,
+    /// Applied on a break condition or once the UART was idle for [`Self::uart_idle_timeout`]
+    pub uart_disconnect: DisconnectPolicy,
+    /// How long the UART may go without receiving data before the link counts as lost, zero
+    /// disables the idle detection
+    pub uart_idle_timeout: Interval,
+    /// Indexed by [`Port::SERIAL`], a port can take control from an owner of lower priority
+    pub port_priority: [u8; 2],
+    /// How often the control loop applies the motor setpoints, in Hz
+    pub control_loop_rate: u16,
+}
+
+impl DeviceConfig {
+    pub const DEFAULT: Self = Self {
+        motor_map: MotorMap::IDENTITY,
+        motors: [MotorConfig::DEFAULT; 4],
+        current_gain: [Self::UNITY_GAIN; 4],
+        current_filter: Interval(10),
+        i2c_address: 0x42,
+        uart_baud: 115_200,
+        device_name: FixedString([0; 16]),
+        thermal_limit: Temperature(0),
+        usb_disconnect: DisconnectPolicy::DisarmAfter(Interval(250)),
+        uart_disconnect: DisconnectPolicy::DisarmAfter(Interval(250)),
+        uart_idle_timeout: Interval(0),
+        port_priority: [0; 2],
+        control_loop_rate: 1000,
+    };
+
+    /// Current gain that leaves the sensed current unscaled
+    pub const UNITY_GAIN: u16 = 10_000;
+    /// 7 bit addresses that are not reserved by the I2C specification
+    pub const I2C_ADDRESSES: RangeInclusive<u8> = 0x08..=0x77;
+    /// Thermal limits that can be set, up to the highest rated junction temperature
+    pub const THERMAL_LIMITS: RangeInclusive<i16> = 0..=1250;
+    /// Control loop rates the firmware can keep up with
+    pub const CONTROL_LOOP_RATES: RangeInclusive<u16> = 1..=10_000;
+
+    pub fn is_valid(&self) -> bool {
+        self.motor_map.is_valid()
+            && self.current_gain.iter().all(|gain| *gain > 0)
+            && Self::I2C_ADDRESSES.contains(&self.i2c_address)
+            && self.uart_baud > 0
+            && Self::THERMAL_LIMITS.contains(&self.thermal_limit.0)
+            && self.motors.iter().all(|motor| motor.failsafe.is_valid())
+            && Self::CONTROL_LOOP_RATES.contains(&self.control_loop_rate)
+    }
+
+    pub fn get(&self, param: ConfigParam) -> Result<ParamValue, ConfigRejection> {
+        let value = match param {
+            ConfigParam::MotorMap => ParamValue::MotorMap(self.motor_map),
+            ConfigParam::CurrentLimit(motor_id) => {
+                ParamValue::Current(self.motor(motor_id)?.current_limit.clone())
+            }
+            ConfigParam::TripTime(motor_id) => {
+                ParamValue::Interval(self.motor(motor_id)?.trip_time.clone())
+            }
+            ConfigParam::CurrentCeiling(motor_id) => {
+                ParamValue::Current(self.motor(motor_id)?.current_ceiling.clone())
+            }
+            ConfigParam::Acceleration(motor_id) => {
+                ParamValue::RampRate(self.motor(motor_id)?.acceleration.clone())
+            }
+            ConfigParam::Deceleration(motor_id) => {
+                ParamValue::RampRate(self.motor(motor_id)?.deceleration.clone())
+            }
+            ConfigParam::Failsafe(motor_id) => {
+                ParamValue::Failsafe(self.motor(motor_id)?.failsafe.clone())
+            }
+            ConfigParam::I2cAddress => ParamValue::U8(self.i2c_address),
+            ConfigParam::UartBaud => ParamValue::U32(self.uart_baud),
+            ConfigParam::DeviceName => ParamValue::String(self.device_name),
+            ConfigParam::CurrentGain(output) => ParamValue::U16(*self.current_gain(output)?),
+            ConfigParam::CurrentFilter => ParamValue::Interval(self.current_filter.clone()),
+            ConfigParam::ThermalLimit => ParamValue::Temperature(self.thermal_limit),
+            ConfigParam::UsbDisconnect => ParamValue::DisconnectPolicy(self.usb_disconnect.clone()),
+            ConfigParam::UartDisconnect => {
+                ParamValue::DisconnectPolicy(self.uart_disconnect.clone())
+            }
+            ConfigParam::UartIdleTimeout => ParamValue::Interval(self.uart_idle_timeout.clone()),
+            ConfigParam::PortPriority(port) => ParamValue::U8(*self.port_priority(port)?),
+            ConfigParam::ControlLoopRate => ParamValue::U16(self.control_loop_rate),
+        };
+
+        Ok(value)
+    }
+
+    /// Sets a single setting, the config is left untouched when the value is rejected
+    pub fn set(&mut self, param: ConfigParam, value: ParamValue) -> Result<(), ConfigRejection> {
+        match (param, value) {
+            (ConfigParam::MotorMap, ParamValue::MotorMap(map)) => {
+                if !map.is_valid() {
+                    return Err(ConfigRejection::OutOfRange);
+                }
+
+                self.motor_map = map;
+            }
+            (ConfigParam::CurrentLimit(motor_id), ParamValue::Current(limit)) => {
+                self.motor_mut(motor_id)?.current_limit = Self::check_current(limit)?;
+            }
+            (ConfigParam::TripTime(motor_id), ParamValue::Interval(trip_time)) => {
+                self.motor_mut(motor_id)?.trip_time = trip_time;
+            }
+            (ConfigParam::CurrentCeiling(motor_id), ParamValue::Current(ceiling)) => {
+                self.motor_mut(motor_id)?.current_ceiling = Self::check_current(ceiling)?;
+            }
+            (ConfigParam::Acceleration(motor_id), ParamValue::RampRate(rate)) => {
+                self.motor_mut(motor_id)?.acceleration = rate;
+            }
+            (ConfigParam::Deceleration(motor_id), ParamValue::RampRate(rate)) => {
+                self.motor_mut(motor_id)?.deceleration = rate;
+            }
+            (ConfigParam::Failsafe(motor_id), ParamValue::Failsafe(failsafe)) => {
+                if !failsafe.is_valid() {
+                    return Err(ConfigRejection::OutOfRange);
+                }
+
+                self.motor_mut(motor_id)?.failsafe = failsafe;
+            }
+            (ConfigParam::I2cAddress, ParamValue::U8(address)) => {
+                if !Self::I2C_ADDRESSES.contains(&address) {
+                    return Err(ConfigRejection::OutOfRange);
+                }
+
+                self.i2c_address = address;
+            }
+            (ConfigParam::UartBaud, ParamValue::U32(baud)) => {
+                if baud == 0 {
+                    return Err(ConfigRejection::OutOfRange);
+                }
+
+                self.uart_baud = baud;
+            }
+            (ConfigParam::DeviceName, ParamValue::String(name)) => {
+                self.device_name = name;
+            }
+            (ConfigParam::CurrentGain(output), ParamValue::U16(gain)) => {
+                if gain == 0 {
+                    return Err(ConfigRejection::OutOfRange);
+                }
+
+                *self.current_gain_mut(output)? = gain;
+            }
+            (ConfigParam::CurrentFilter, ParamValue::Interval(time_constant)) => {
+                self.current_filter = time_constant;
+            }
+            (ConfigParam::ThermalLimit, ParamValue::Temperature(limit)) => {
+                if !Self::THERMAL_LIMITS.contains(&limit.0) {
+                    return Err(ConfigRejection::OutOfRange);
+                }
+
+                self.thermal_limit = limit;
+            }
+            (ConfigParam::UsbDisconnect, ParamValue::DisconnectPolicy(policy)) => {
+                self.usb_disconnect = policy;
+            }
+            (ConfigParam::UartDisconnect, ParamValue::DisconnectPolicy(policy)) => {
+                self.uart_disconnect = policy;
+            }
+            (ConfigParam::UartIdleTimeout, ParamValue::Interval(timeout)) => {
+                self.uart_idle_timeout = timeout;
+            }
+            (ConfigParam::PortPriority(port), ParamValue::U8(priority)) => {
+                *self.port_priority_mut(port)? = priority;
+            }
+            (ConfigParam::ControlLoopRate, ParamValue::U16(rate_hz)) => {
+                if !Self::CONTROL_LOOP_RATES.contains(&rate_hz) {
+                    return Err(ConfigRejection::OutOfRange);
+                }
+
+                self.control_loop_rate = rate_hz;
+            }
+            _ => return Err(ConfigRejection::WrongType),
+        }
+
+        Ok(())
+    }
+
+    fn motor(&self, motor_id: u8) -> Result<&MotorConfig, ConfigRejection> {
+        self.motors
+            .get(motor_id as usize)
+            .ok_or(ConfigRejection::InvalidMotor)
+    }
+
+    fn motor_mut(&mut self, motor_id: u8) -> Result<&mut MotorConfig, ConfigRejection> {
+        self.motors
+            .get_mut(motor_id as usize)
+            .ok_or(ConfigRejection::InvalidMotor)
+    }
+
+    fn current_gain(&self, output: u8) -> Result<&u16, ConfigRejection> {
+        self.current_gain
+            .get(output as usize)
+            .ok_or(ConfigRejection::InvalidMotor)
+    }
+
+    fn current_gain_mut(&mut self, output: u8) -> Result<&mut u16, ConfigRejection> {
+        self.current_gain
+            .get_mut(output as usize)
+            .ok_or(ConfigRejection::InvalidMotor)
+    }
+
+    fn port_priority(&self, port: u8) -> Result<&u8, ConfigRejection> {
+        self.port_priority
+            .get(port as usize)
+            .ok_or(ConfigRejection::InvalidMotor)
+    }
+
+    fn port_priority_mut(&mut self, port: u8) -> Result<&mut u8, ConfigRejection> {
+        self.port_priority
+            .get_mut(port as usize)
+            .ok_or(ConfigRejection::InvalidMotor)
+    }
+
+    /// Rejects the reserved values, which are meaningless as a setting
+    fn check_current(current: CurrentDraw) -> Result<CurrentDraw, ConfigRejection> {
+        if current.is_no_reading() || current.is_saturated() {
+            return Err(ConfigRejection::OutOfRange);
+        }
+
+        Ok(current)
+    }
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Identifies a single setting of the [`DeviceConfig`]
+///
+/// Per motor settings carry the motor id, calibration settings carry the physical output and port
+/// settings carry the [`Port`] index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum ConfigParam {
+    MotorMap,
+    CurrentLimit(u8),
+    TripTime(u8),
+    CurrentCeiling(u8),
+    Acceleration(u8),
+    Deceleration(u8),
+    I2cAddress,
+    UartBaud,
+    DeviceName,
+    CurrentGain(u8),
+    CurrentFilter,
+    ThermalLimit,
+    UsbDisconnect,
+    UartDisconnect,
+    UartIdleTimeout,
+    PortPriority(u8),
+    Failsafe(u8),
+    ControlLoopRate,
+}
+
+impl ConfigParam {
+    /// Every setting of the [`DeviceConfig`]
+    pub fn all() -> impl Iterator<Item = ConfigParam> {
+        let motors = (0..4).flat_map(|motor_id| {
+            [
+                ConfigParam::CurrentLimit(motor_id),
+                ConfigParam::TripTime(motor_id),
+                ConfigParam::CurrentCeiling(motor_id),
+                ConfigParam::Acceleration(motor_id),
+                ConfigParam::Deceleration(motor_id),
+                ConfigParam::Failsafe(motor_id),
+            ]
+        });
+
+        let outputs = (0..4).map(ConfigParam::CurrentGain);
+
+        [ConfigParam::MotorMap]
+            .into_iter()
+            .chain(motors)
+            .chain(outputs)
+            .chain([
+                ConfigParam::CurrentFilter,
+                ConfigParam::I2cAddress,
+                ConfigParam::UartBaud,
+                ConfigParam::DeviceName,
+                ConfigParam::ThermalLimit,
+                ConfigParam::UsbDisconnect,
+                ConfigParam::UartDisconnect,
+                ConfigParam::UartIdleTimeout,
+                ConfigParam::ControlLoopRate,
+            ])
+            .chain((0..Port::SERIAL.len() as u8).map(ConfigParam::PortPriority))
+    }
+}
+
+/// Value of a [`ConfigParam`], the variant must match the type of the setting
+#[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+pub enum ParamValue {
+    MotorMap(MotorMap),
+    Current(CurrentDraw),
+    Interval(Interval),
+    RampRate(RampRate),
+    U8(u8),
+    U32(u32),
+    String(FixedString),
+    U16(u16),
+    Temperature(Temperature),
+    DisconnectPolicy(DisconnectPolicy),
+    Failsafe(Failsafe),
+}
+
+/// Reason a [`ConfigParam`] could not be read or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum ConfigRejection {
+    /// The parameter references a motor, output or port that does not exist
+    InvalidMotor,
+    /// The value does not have the type of the setting
+    WrongType,
+    /// The value is outside of the range allowed for the setting
+    OutOfRange,
+
+    #[serde(other)]
+    Unknown,
+}
+
 /// Host -> Motor controller
 pub mod h2c {
     use postcard::experimental::max_size::MaxSize;
     use serde::{Deserialize, Serialize};
 
-    use super::{Interval, Motors, Speed};
+    use super::{
+        ConfigParam, CurrentDraw, CurrentKind, DeviceConfig, Interval, MotorMap, Motors,
+        ParamValue, RampRate, Speed,
+    };
 
     #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
     pub enum PacketH2C {
@@ -100,12 +708,41 @@ pub mod h2c {
         StartStream(StartStream),
         SetSpeed(SetSpeed),
         SetArmed(SetArmed),
+        SetSpeeds(SetSpeeds),
+        SetCurrentLimit(SetCurrentLimit),
+        ClearFaults(ClearFaults),
+        SetCurrentCeiling(SetCurrentCeiling),
+        SetRampRate(SetRampRate),
+        SetControlLoopRate(SetControlLoopRate),
+        ReadControlLoopStats,
+        ResetControlLoopStats,
+        SetMotorMap(SetMotorMap),
+        ReadMotorMap,
+        ReadConfig,
+        WriteConfig(WriteConfig),
+        CommitConfig,
+        FactoryReset,
+        GetConfig(GetConfig),
+        SetConfig(SetConfig),
+        CalibrateCurrent,
+        ReadCharge,
+        ResetCharge(ResetCharge),
+        ReadDeviceStatus,
+        ClaimControl,
+        ReleaseControl,
+        ReadControlStatus,
+        EmergencyStop,
+        ClearEmergencyStop(ClearEmergencyStop),
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
     pub struct StartStream {
         pub motors: Motors,
         pub interval: Interval,
+        /// Measurement reported as the current draw of the streamed motors
+        pub current: CurrentKind,
+        /// Also report the charge drawn by the streamed motors
+        pub charge: bool,
     }
 
     impl From<StartStream> for PacketH2C {
@@ -126,6 +763,162 @@ pub mod h2c {
         }
     }
 
+    /// Sets the speed of every motor with a `Some` entry at the same instant, indexed by motor id
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct SetSpeeds {
+        pub speeds: [Option<Speed>; 4],
+    }
+
+    impl From<SetSpeeds> for PacketH2C {
+        fn from(value: SetSpeeds) -> Self {
+            PacketH2C::SetSpeeds(value)
+        }
+    }
+
+    /// Cuts the output of a motor once it draws more than `limit` for `trip_time`
+    ///
+    /// The trip latches until cleared with [`ClearFaults`], a limit of zero disables it
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct SetCurrentLimit {
+        pub motors: Motors,
+        pub limit: CurrentDraw,
+        pub trip_time: Interval,
+    }
+
+    impl From<SetCurrentLimit> for PacketH2C {
+        fn from(value: SetCurrentLimit) -> Self {
+            PacketH2C::SetCurrentLimit(value)
+        }
+    }
+
+    /// Holds the current of a motor at `ceiling` by lowering its duty cycle, zero disables it
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct SetCurrentCeiling {
+        pub motors: Motors,
+        pub ceiling: CurrentDraw,
+    }
+
+    impl From<SetCurrentCeiling> for PacketH2C {
+        fn from(value: SetCurrentCeiling) -> Self {
+            PacketH2C::SetCurrentCeiling(value)
+        }
+    }
+
+    /// Clears latched faults so the motors can run again
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ClearFaults {
+        pub motors: Motors,
+    }
+
+    impl From<ClearFaults> for PacketH2C {
+        fn from(value: ClearFaults) -> Self {
+            PacketH2C::ClearFaults(value)
+        }
+    }
+
+    /// Limits how fast the applied speed of a motor moves away from (acceleration) and towards
+    /// (deceleration) zero
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct SetRampRate {
+        pub motors: Motors,
+        pub acceleration: RampRate,
+        pub deceleration: RampRate,
+    }
+
+    impl From<SetRampRate> for PacketH2C {
+        fn from(value: SetRampRate) -> Self {
+            PacketH2C::SetRampRate(value)
+        }
+    }
+
+    /// Sets how often the firmware control loop applies the motor setpoints
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct SetControlLoopRate {
+        pub rate_hz: u16,
+    }
+
+    impl From<SetControlLoopRate> for PacketH2C {
+        fn from(value: SetControlLoopRate) -> Self {
+            PacketH2C::SetControlLoopRate(value)
+        }
+    }
+
+    /// Remaps the logical motors onto the physical outputs, disarming every motor
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct SetMotorMap {
+        pub map: MotorMap,
+    }
+
+    impl From<SetMotorMap> for PacketH2C {
+        fn from(value: SetMotorMap) -> Self {
+            PacketH2C::SetMotorMap(value)
+        }
+    }
+
+    /// Replaces the configuration in use, it is only persisted by [`PacketH2C::CommitConfig`]
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct WriteConfig {
+        pub config: DeviceConfig,
+    }
+
+    impl From<WriteConfig> for PacketH2C {
+        fn from(value: WriteConfig) -> Self {
+            PacketH2C::WriteConfig(value)
+        }
+    }
+
+    /// Reads a single setting, answered with [`super::c2h::ConfigValue`] or
+    /// [`super::c2h::ConfigRejected`]
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct GetConfig {
+        pub param: ConfigParam,
+    }
+
+    impl From<GetConfig> for PacketH2C {
+        fn from(value: GetConfig) -> Self {
+            PacketH2C::GetConfig(value)
+        }
+    }
+
+    /// Changes a single setting of the configuration in use, answered with the new
+    /// [`super::c2h::ConfigValue`] or [`super::c2h::ConfigRejected`]
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct SetConfig {
+        pub param: ConfigParam,
+        pub value: ParamValue,
+    }
+
+    impl From<SetConfig> for PacketH2C {
+        fn from(value: SetConfig) -> Self {
+            PacketH2C::SetConfig(value)
+        }
+    }
+
+    /// Restarts the charge counters of the given motors from zero
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ResetCharge {
+        pub motors: Motors,
+    }
+
+    impl From<ResetCharge> for PacketH2C {
+        fn from(value: ResetCharge) -> Self {
+            PacketH2C::ResetCharge(value)
+        }
+    }
+
+    /// Releases a latched [`PacketH2C::EmergencyStop`], the motors stay disarmed until armed again
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ClearEmergencyStop {
+        /// Must match the token of the latched emergency stop
+        pub token: u32,
+    }
+
+    impl From<ClearEmergencyStop> for PacketH2C {
+        fn from(value: ClearEmergencyStop) -> Self {
+            PacketH2C::ClearEmergencyStop(value)
+        }
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
     pub struct Ping {
         pub id: u8,
@@ -158,7 +951,10 @@ pub mod c2h {
     use postcard::experimental::max_size::MaxSize;
     use serde::{Deserialize, Serialize};
 
-    use super::{CurrentDraw, Speed};
+    use super::{
+        Charge, ConfigParam, ConfigRejection, CurrentDraw, DeviceConfig, Failsafe, FixedString,
+        MotorMap, ParamValue, Port, SemanticVersion, Speed, Temperature,
+    };
 
     #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
     pub enum PacketC2H {
@@ -170,15 +966,36 @@ pub mod c2h {
         // Unstable packets
         SoftwareDataResponse(SoftwareDataResponse),
         MotorState(MotorState),
+        MotorStates(MotorStates),
+        MotorError(MotorError),
+        FaultEvent(FaultEvent),
+        ControlLoopStats(ControlLoopStats),
+        MotorMapResponse(MotorMapResponse),
+        ConfigResponse(ConfigResponse),
+        ConfigValue(ConfigValue),
+        ConfigRejected(ConfigRejected),
+        CurrentCalibrated(CurrentCalibrated),
+        ChargeResponse(ChargeResponse),
+        DeviceStatus(DeviceStatus),
+        ControlStatus(ControlStatus),
+        EmergencyStopEvent(EmergencyStopEvent),
+        DeadlineExpired(DeadlineExpired),
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
     pub struct MotorState {
         pub motor_id: u8,
+        /// Speed last commanded by a host
         pub last_speed: Speed,
+        /// Speed applied to the driver after ramping and current limiting
+        pub applied_speed: Speed,
         pub current_draw: CurrentDraw,
         pub is_fault: bool,
         pub is_enabled: bool,
+        /// The overcurrent trip latched, see [`super::h2c::SetCurrentLimit`]
+        pub is_overcurrent: bool,
+        /// Only present when requested by [`super::h2c::StartStream::charge`]
+        pub charge: Option<Charge>,
     }
 
     impl From<MotorState> for PacketC2H {
@@ -187,6 +1004,171 @@ pub mod c2h {
         }
     }
 
+    /// State of every streamed motor sampled at the same instant, indexed by motor id
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct MotorStates {
+        pub states: [Option<MotorState>; 4],
+    }
+
+    impl From<MotorStates> for PacketC2H {
+        fn from(value: MotorStates) -> Self {
+            PacketC2H::MotorStates(value)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ControlLoopStats {
+        pub rate_hz: u16,
+        /// Ticks run since the statistics were last reset
+        pub ticks: u32,
+        /// Ticks that finished after the next tick was due
+        pub overruns: u32,
+        /// Longest time spent in a single tick
+        pub max_tick_us: u32,
+    }
+
+    impl From<ControlLoopStats> for PacketC2H {
+        fn from(value: ControlLoopStats) -> Self {
+            PacketC2H::ControlLoopStats(value)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct MotorMapResponse {
+        pub map: MotorMap,
+    }
+
+    impl From<MotorMapResponse> for PacketC2H {
+        fn from(value: MotorMapResponse) -> Self {
+            PacketC2H::MotorMapResponse(value)
+        }
+    }
+
+    /// Configuration in use, which may differ from the one persisted in flash
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ConfigResponse {
+        pub config: DeviceConfig,
+    }
+
+    impl From<ConfigResponse> for PacketC2H {
+        fn from(value: ConfigResponse) -> Self {
+            PacketC2H::ConfigResponse(value)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ConfigValue {
+        pub param: ConfigParam,
+        pub value: ParamValue,
+    }
+
+    impl From<ConfigValue> for PacketC2H {
+        fn from(value: ConfigValue) -> Self {
+            PacketC2H::ConfigValue(value)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ConfigRejected {
+        pub param: ConfigParam,
+        pub reason: ConfigRejection,
+    }
+
+    impl From<ConfigRejected> for PacketC2H {
+        fn from(value: ConfigRejected) -> Self {
+            PacketC2H::ConfigRejected(value)
+        }
+    }
+
+    /// Zero offsets measured by [`super::h2c::PacketH2C::CalibrateCurrent`], indexed by physical
+    /// output
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct CurrentCalibrated {
+        pub offsets: [u16; 4],
+    }
+
+    impl From<CurrentCalibrated> for PacketC2H {
+        fn from(value: CurrentCalibrated) -> Self {
+            PacketC2H::CurrentCalibrated(value)
+        }
+    }
+
+    /// Charge drawn by every motor since its counter was last reset, indexed by motor id
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ChargeResponse {
+        pub charge: [Charge; 4],
+    }
+
+    impl From<ChargeResponse> for PacketC2H {
+        fn from(value: ChargeResponse) -> Self {
+            PacketC2H::ChargeResponse(value)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct DeviceStatus {
+        /// Low pass filtered die temperature
+        pub temperature: Temperature,
+        /// The die is above [`DeviceConfig::thermal_limit`], motors can not be armed until it
+        /// cools down
+        pub overheated: bool,
+        /// Cause of the last reset of the motor controller
+        pub reset_reason: ResetReason,
+        /// Token of the latched emergency stop, `None` when not latched
+        pub emergency_stop: Option<u32>,
+    }
+
+    impl From<DeviceStatus> for PacketC2H {
+        fn from(value: DeviceStatus) -> Self {
+            PacketC2H::DeviceStatus(value)
+        }
+    }
+
+    /// Sent in reply to the control packets and on every interface whenever the owner changes
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct ControlStatus {
+        /// `None` when every interface may send motion and arming commands
+        pub owner: Option<Port>,
+        /// The interface this packet was sent on holds control
+        pub is_owner: bool,
+    }
+
+    impl From<ControlStatus> for PacketC2H {
+        fn from(value: ControlStatus) -> Self {
+            PacketC2H::ControlStatus(value)
+        }
+    }
+
+    /// Sent on every interface whenever an emergency stop latches or is cleared
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct EmergencyStopEvent {
+        pub latched: bool,
+        /// Needed by [`super::h2c::ClearEmergencyStop`]
+        pub token: u32,
+        /// Interface that sent the emergency stop or cleared it
+        pub source: Port,
+    }
+
+    impl From<EmergencyStopEvent> for PacketC2H {
+        fn from(value: EmergencyStopEvent) -> Self {
+            PacketC2H::EmergencyStopEvent(value)
+        }
+    }
+
+    /// Sent on every interface when the [`super::h2c::SetArmed`] deadline elapses and the motors
+    /// are disarmed
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct DeadlineExpired {
+        /// Failsafe applied to every motor, indexed by motor id
+        pub failsafe: [Failsafe; 4],
+    }
+
+    impl From<DeadlineExpired> for PacketC2H {
+        fn from(value: DeadlineExpired) -> Self {
+            PacketC2H::DeadlineExpired(value)
+        }
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
     pub struct Pong {
         pub id: u8,
@@ -211,7 +1193,25 @@ pub mod c2h {
 
     #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
     pub struct SoftwareDataResponse {
-        pub version: u16,
+        /// Version of the firmware crate
+        pub version: SemanticVersion,
+        /// Commit the firmware was built from, all zeros when unknown
+        pub git_hash: [u8; 20],
+        /// Whether the working tree had uncommitted changes
+        pub git_dirty: bool,
+        pub build_profile: BuildProfile,
+        /// Seconds since the unix epoch
+        pub build_timestamp: u64,
+        pub board: FixedString,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+    pub enum BuildProfile {
+        Debug,
+        Release,
+
+        #[serde(other)]
+        Unknown,
     }
 
     impl From<SoftwareDataResponse> for PacketC2H {
@@ -220,11 +1220,25 @@ pub mod c2h {
         }
     }
 
-    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
     pub enum Error {
         DecodingError,
         DecodingBufferOverflow,
         Unimplemented,
+        /// A configuration was rejected because a setting is out of range
+        InvalidConfig,
+        /// The request is refused while a motor is armed
+        Armed,
+        /// Reading or writing the flash failed
+        FlashError,
+        /// Arming is refused while the die is above the thermal limit
+        Overheated,
+        /// Motion and arming commands are refused while another interface holds control
+        NotOwner,
+        /// Arming is refused while an emergency stop is latched
+        EmergencyStop,
+        /// The token does not match the latched emergency stop
+        WrongToken,
 
         #[serde(other)]
         Unknown,
@@ -235,4 +1249,439 @@ pub mod c2h {
             PacketC2H::Error(value)
         }
     }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct MotorError {
+        /// [`MotorError::NO_MOTOR`] when the error is not related to a motor
+        pub motor_id: u8,
+        pub fault: MotorFault,
+    }
+
+    impl MotorError {
+        pub const NO_MOTOR: u8 = 0xFF;
+    }
+
+    impl From<MotorError> for PacketC2H {
+        fn from(value: MotorError) -> Self {
+            PacketC2H::MotorError(value)
+        }
+    }
+
+    /// Sent to every interface when the driver of a motor asserts or clears its nFAULT output
+    #[derive(Debug, Clone, Serialize, Deserialize, MaxSize)]
+    pub struct FaultEvent {
+        pub motor_id: u8,
+        pub asserted: bool,
+        /// Microseconds since the motor controller booted
+        pub timestamp_us: u64,
+        /// Number of faults this motor had since the motor controller booted
+        pub fault_count: u32,
+    }
+
+    impl From<FaultEvent> for PacketC2H {
+        fn from(value: FaultEvent) -> Self {
+            PacketC2H::FaultEvent(value)
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+    pub enum MotorFault {
+        /// The motor driver is asserting its nFAULT output
+        DriverFault,
+        /// The motor drew more current than its limit allows
+        Overcurrent,
+        /// A command was rejected because the motor is disarmed
+        Disarmed,
+        /// A command referenced a motor that does not exist
+        InvalidMotor,
+
+        #[serde(other)]
+        Unknown,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+    pub enum ResetReason {
+        /// Power on or the RUN pin
+        PowerOn,
+        /// The hardware watchdog expired because the firmware stopped responding
+        WatchdogTimeout,
+        /// The firmware reset itself
+        Forced,
+
+        #[serde(other)]
+        Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Serialize, de::DeserializeOwned};
+
+    use crate::{
+        decoder::{FeedResult, PackerDecoder},
+        encoder::encode_packet,
+        *,
+    };
+
+    fn to_bytes<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut buffer = [0; MAX_FRAME_SIZE];
+        postcard::to_slice(value, &mut buffer).unwrap().to_vec()
+    }
+
+    /// Frames `packet` like the links do and decodes it again
+    fn round_trip<T: Serialize + DeserializeOwned>(packet: &T) -> T {
+        let mut buffer = [0; MAX_FRAME_SIZE];
+        let frame = encode_packet(packet, &mut buffer).unwrap();
+
+        let mut decoder = PackerDecoder::<MAX_FRAME_SIZE>::new();
+        match decoder.feed(frame) {
+            FeedResult::Success { data, remaining } => {
+                assert!(remaining.is_empty());
+                data
+            }
+            _ => panic!("frame did not decode"),
+        }
+    }
+
+    /// Every field at the value with the longest encoding
+    fn largest_config() -> DeviceConfig {
+        let motor = MotorConfig {
+            current_limit: CurrentDraw(u16::MAX - 2),
+            trip_time: Interval(u16::MAX),
+            current_ceiling: CurrentDraw(u16::MAX - 2),
+            acceleration: RampRate(u16::MAX),
+            deceleration: RampRate(u16::MAX),
+            failsafe: Failsafe::Hold {
+                speed: Speed(-Failsafe::MAX_HOLD_SPEED.0),
+                duration: Failsafe::MAX_HOLD_DURATION,
+            },
+        };
+
+        DeviceConfig {
+            motor_map: MotorMap {
+                outputs: [3, 2, 1, 0],
+                inverted: Motors::all(),
+            },
+            motors: [motor.clone(), motor.clone(), motor.clone(), motor],
+            current_gain: [u16::MAX; 4],
+            current_filter: Interval(u16::MAX),
+            i2c_address: *DeviceConfig::I2C_ADDRESSES.end(),
+            uart_baud: u32::MAX,
+            device_name: FixedString([0xff; 16]),
+            thermal_limit: Temperature(*DeviceConfig::THERMAL_LIMITS.end()),
+            usb_disconnect: DisconnectPolicy::DisarmAfter(Interval(u16::MAX)),
+            uart_disconnect: DisconnectPolicy::DisarmAfter(Interval(u16::MAX)),
+            uart_idle_timeout: Interval(u16::MAX),
+            port_priority: [u8::MAX; 2],
+            control_loop_rate: *DeviceConfig::CONTROL_LOOP_RATES.end(),
+        }
+    }
+
+    #[test]
+    fn current_draw_saturates() {
+        assert_eq!(CurrentDraw::from_f32_amps(1.2344).0, 1234);
+        assert_eq!(CurrentDraw::from_f32_amps(1.2345).0, 1235);
+        assert!(CurrentDraw::from_f32_amps(65.534).is_saturated());
+        assert!(CurrentDraw::from_f32_amps(f32::INFINITY).is_saturated());
+        assert!(!CurrentDraw::from_f32_amps(65.533).is_saturated());
+    }
+
+    #[test]
+    fn current_draw_no_reading() {
+        assert!(CurrentDraw::from_f32_amps(-1.0).is_no_reading());
+        assert!(!CurrentDraw::from_f32_amps(0.0).is_no_reading());
+        assert!(CurrentDraw::NO_READING.as_f32_amps() < 0.0);
+        assert_eq!(CurrentDraw::SATURATED.as_f32_amps(), 65.534);
+    }
+
+    #[test]
+    fn temperature_saturates_above_no_reading() {
+        assert_eq!(Temperature::from_f32_celsius(25.04), Temperature(250));
+        assert_eq!(Temperature::from_f32_celsius(-25.06), Temperature(-251));
+        assert_eq!(Temperature::from_f32_celsius(1e6), Temperature(i16::MAX));
+        assert_eq!(
+            Temperature::from_f32_celsius(-1e6),
+            Temperature(i16::MIN + 1)
+        );
+        assert!(!Temperature::from_f32_celsius(f32::NEG_INFINITY).is_no_reading());
+    }
+
+    #[test]
+    fn temperature_no_reading() {
+        assert!(Temperature::NO_READING.is_no_reading());
+        assert_eq!(Temperature::NO_READING.as_f32_celsius(), None);
+        assert_eq!(Temperature(-400).as_f32_celsius(), Some(-40.0));
+    }
+
+    #[test]
+    fn ramp_rate() {
+        assert_eq!(RampRate::from_f32(f32::INFINITY).0, RampRate::UNLIMITED.0);
+        assert_eq!(RampRate::UNLIMITED.as_f32(), None);
+        assert_eq!(RampRate::from_f32(2.5).as_f32(), Some(2.5));
+        // Tiny rates must not turn into unlimited ones
+        assert_eq!(RampRate::from_f32(0.0).0, 1);
+        assert_eq!(RampRate::from_f32(-1.0).0, 1);
+        assert_eq!(RampRate::from_f32(1e6).0, u16::MAX);
+    }
+
+    #[test]
+    fn fixed_string_truncates_on_char_boundary() {
+        assert_eq!(FixedString::new("motor").as_str(), "motor");
+        assert_eq!(FixedString::new("").as_str(), "");
+        assert_eq!(
+            FixedString::new("0123456789abcdefgh").as_str(),
+            "0123456789abcdef"
+        );
+        // The 'é' occupies bytes 15 and 16 and does not fit
+        assert_eq!(
+            FixedString::new("0123456789abcdeé").as_str(),
+            "0123456789abcde"
+        );
+        assert_eq!(FixedString::new("ééééééééé").as_str(), "éééééééé");
+    }
+
+    #[test]
+    fn motor_map_is_valid() {
+        assert!(MotorMap::IDENTITY.is_valid());
+
+        let map = |outputs| MotorMap {
+            outputs,
+            inverted: Motors::empty(),
+        };
+        assert!(map([3, 1, 0, 2]).is_valid());
+        assert!(!map([0, 0, 1, 2]).is_valid());
+        assert!(!map([0, 1, 2, 4]).is_valid());
+        assert!(!map([u8::MAX; 4]).is_valid());
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(DeviceConfig::DEFAULT.is_valid());
+        assert!(largest_config().is_valid());
+    }
+
+    #[test]
+    fn config_is_valid_checks_ranges() {
+        let invalid = [
+            DeviceConfig {
+                i2c_address: 0x78,
+                ..DeviceConfig::DEFAULT
+            },
+            DeviceConfig {
+                uart_baud: 0,
+                ..DeviceConfig::DEFAULT
+            },
+            DeviceConfig {
+                thermal_limit: Temperature(-1),
+                ..DeviceConfig::DEFAULT
+            },
+            DeviceConfig {
+                current_gain: [DeviceConfig::UNITY_GAIN, 0, 0, 0],
+                ..DeviceConfig::DEFAULT
+            },
+            DeviceConfig {
+                control_loop_rate: 0,
+                ..DeviceConfig::DEFAULT
+            },
+        ];
+
+        for config in invalid {
+            assert!(!config.is_valid(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn config_set_rejects_out_of_range() {
+        let cases = [
+            (ConfigParam::I2cAddress, ParamValue::U8(0x07)),
+            (ConfigParam::I2cAddress, ParamValue::U8(0x78)),
+            (ConfigParam::UartBaud, ParamValue::U32(0)),
+            (
+                ConfigParam::ThermalLimit,
+                ParamValue::Temperature(Temperature(1251)),
+            ),
+            (ConfigParam::CurrentGain(0), ParamValue::U16(0)),
+            (ConfigParam::ControlLoopRate, ParamValue::U16(0)),
+            (ConfigParam::ControlLoopRate, ParamValue::U16(10_001)),
+            (
+                ConfigParam::CurrentLimit(0),
+                ParamValue::Current(CurrentDraw::NO_READING),
+            ),
+            (
+                ConfigParam::CurrentCeiling(0),
+                ParamValue::Current(CurrentDraw::SATURATED),
+            ),
+            (
+                ConfigParam::MotorMap,
+                ParamValue::MotorMap(MotorMap {
+                    outputs: [0; 4],
+                    inverted: Motors::empty(),
+                }),
+            ),
+            (
+                ConfigParam::Failsafe(0),
+                ParamValue::Failsafe(Failsafe::Hold {
+                    speed: Speed(Failsafe::MAX_HOLD_SPEED.0 + 1),
+                    duration: Interval(0),
+                }),
+            ),
+            (
+                ConfigParam::Failsafe(0),
+                ParamValue::Failsafe(Failsafe::Hold {
+                    speed: Speed(0),
+                    duration: Interval(Failsafe::MAX_HOLD_DURATION.0 + 1),
+                }),
+            ),
+        ];
+
+        for (param, value) in cases {
+            let mut config = DeviceConfig::DEFAULT;
+            let result = config.set(param, value.clone());
+
+            assert_eq!(
+                result,
+                Err(ConfigRejection::OutOfRange),
+                "{param:?} {value:?}"
+            );
+            assert_eq!(to_bytes(&config), to_bytes(&DeviceConfig::DEFAULT));
+        }
+    }
+
+    #[test]
+    fn config_set_accepts_range_ends() {
+        let cases = [
+            (ConfigParam::I2cAddress, ParamValue::U8(0x08)),
+            (ConfigParam::I2cAddress, ParamValue::U8(0x77)),
+            (
+                ConfigParam::ThermalLimit,
+                ParamValue::Temperature(Temperature(1250)),
+            ),
+            (ConfigParam::ControlLoopRate, ParamValue::U16(1)),
+            (ConfigParam::ControlLoopRate, ParamValue::U16(10_000)),
+            (
+                ConfigParam::Failsafe(3),
+                ParamValue::Failsafe(Failsafe::Hold {
+                    speed: Speed(-Failsafe::MAX_HOLD_SPEED.0),
+                    duration: Failsafe::MAX_HOLD_DURATION,
+                }),
+            ),
+        ];
+
+        for (param, value) in cases {
+            let mut config = DeviceConfig::DEFAULT;
+            config.set(param, value.clone()).unwrap();
+
+            assert_eq!(
+                to_bytes(&config.get(param).unwrap()),
+                to_bytes(&value),
+                "{param:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn config_set_rejects_wrong_type_and_index() {
+        let mut config = DeviceConfig::DEFAULT;
+
+        assert_eq!(
+            config.set(ConfigParam::I2cAddress, ParamValue::U16(0x42)),
+            Err(ConfigRejection::WrongType)
+        );
+        assert_eq!(
+            config.set(
+                ConfigParam::CurrentLimit(4),
+                ParamValue::Current(CurrentDraw(0))
+            ),
+            Err(ConfigRejection::InvalidMotor)
+        );
+        assert_eq!(
+            config.set(ConfigParam::CurrentGain(4), ParamValue::U16(1)),
+            Err(ConfigRejection::InvalidMotor)
+        );
+        // I2C has no priority slot
+        assert_eq!(
+            config.set(
+                ConfigParam::PortPriority(Port::I2c as u8),
+                ParamValue::U8(1)
+            ),
+            Err(ConfigRejection::InvalidMotor)
+        );
+        assert_eq!(
+            config
+                .get(ConfigParam::PortPriority(Port::I2c as u8))
+                .unwrap_err(),
+            ConfigRejection::InvalidMotor
+        );
+    }
+
+    #[test]
+    fn config_param_all_round_trips() {
+        let params: Vec<_> = ConfigParam::all().collect();
+
+        for (idx, param) in params.iter().enumerate() {
+            assert!(!params[..idx].contains(param), "{param:?} is listed twice");
+        }
+
+        for param in [
+            ConfigParam::MotorMap,
+            ConfigParam::Failsafe(3),
+            ConfigParam::CurrentGain(3),
+            ConfigParam::PortPriority(Port::Uart as u8),
+            ConfigParam::ControlLoopRate,
+        ] {
+            assert!(params.contains(&param), "{param:?} is missing");
+        }
+
+        let source = largest_config();
+        let mut config = DeviceConfig::DEFAULT;
+        for param in params {
+            config.set(param, source.get(param).unwrap()).unwrap();
+        }
+
+        // Every setting is reachable through the parameters
+        assert_eq!(to_bytes(&config), to_bytes(&source));
+    }
+
+    #[test]
+    fn largest_packets_fit_a_frame() {
+        let packet: h2c::PacketH2C = h2c::WriteConfig {
+            config: largest_config(),
+        }
+        .into();
+        let h2c::PacketH2C::WriteConfig(decoded) = round_trip(&packet) else {
+            panic!("decoded another packet");
+        };
+        assert_eq!(to_bytes(&decoded.config), to_bytes(&largest_config()));
+
+        let packet: c2h::PacketC2H = c2h::ConfigResponse {
+            config: largest_config(),
+        }
+        .into();
+        let c2h::PacketC2H::ConfigResponse(decoded) = round_trip(&packet) else {
+            panic!("decoded another packet");
+        };
+        assert_eq!(to_bytes(&decoded.config), to_bytes(&largest_config()));
+    }
+
+    #[test]
+    fn config_params_round_trip() {
+        let source = largest_config();
+
+        for param in ConfigParam::all() {
+            let packet: c2h::PacketC2H = c2h::ConfigValue {
+                param,
+                value: source.get(param).unwrap(),
+            }
+            .into();
+            let c2h::PacketC2H::ConfigValue(decoded) = round_trip(&packet) else {
+                panic!("decoded another packet");
+            };
+
+            assert_eq!(decoded.param, param);
+            assert_eq!(
+                to_bytes(&decoded.value),
+                to_bytes(&source.get(param).unwrap())
+            );
+        }
+    }
 }